@@ -0,0 +1,127 @@
+//! Compares how long it takes to find the black objects in the test images
+//! with the bit-packed mask and the labeling of its runs, and with a flood
+//! fill over a grid of booleans, which the parser used before. Both must find
+//! the same objects, pixel for pixel.
+//!
+//! Run with `cargo bench --bench black_objects`.
+
+use board_parser::label_black_objects;
+use std::fs;
+use std::time::{Duration, Instant};
+
+const ASSETS_DIR: &str = "assets/test";
+const ITERATIONS: u32 = 10;
+// Pixels darker than this are black. The parser's threshold adapts to the
+// light, but a fixed one yields the same pixels for both labelings.
+const BLACK_THRESHOLD: u8 = 60;
+
+fn main() {
+    let mut images: Vec<_> = fs::read_dir(ASSETS_DIR)
+        .expect("Cannot read test assets")
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "jpeg"))
+        .collect();
+    images.sort();
+
+    let (mut total_fill, mut total_mask) =
+        (Duration::new(0, 0), Duration::new(0, 0));
+    for path in images {
+        let image = image::open(&path).expect("Cannot open image").to_luma();
+        let black = black_grid(&image);
+
+        let (fill, mut filled) = time(|| flood_fill_objects(black.clone()));
+        let (mask, mut labeled) = time(|| label_black_objects(&black));
+        sort_objects(&mut filled);
+        sort_objects(&mut labeled);
+        assert!(filled == labeled, "The objects differ in {:?}", path);
+
+        println!(
+            "{:?}: {} objects, flood fill {:?}, mask {:?}",
+            path.file_name().expect("The path is a file"),
+            labeled.len(),
+            fill,
+            mask,
+        );
+        total_fill += fill;
+        total_mask += mask;
+    }
+
+    println!(
+        "total: flood fill {:?}, mask {:?}, {:.1}x faster",
+        total_fill,
+        total_mask,
+        total_fill.as_secs_f64() / total_mask.as_secs_f64(),
+    );
+}
+
+/// The mean duration of the function, and what it returned.
+fn time<T>(f: impl Fn() -> T) -> (Duration, T) {
+    let start = Instant::now();
+    for _ in 1..ITERATIONS {
+        f();
+    }
+    let result = f();
+
+    (start.elapsed() / ITERATIONS, result)
+}
+
+/// Sorts the pixels of each object and then the objects, so that the objects
+/// can be compared whatever order they were found in.
+fn sort_objects(objects: &mut [Vec<(u32, u32)>]) {
+    for object in objects.iter_mut() {
+        object.sort_unstable();
+    }
+    objects.sort_unstable();
+}
+
+fn black_grid(image: &image::GrayImage) -> Vec<Vec<bool>> {
+    (0..image.height())
+        .map(|y| {
+            (0..image.width())
+                .map(|x| image.get_pixel(x, y).0[0] < BLACK_THRESHOLD)
+                .collect()
+        })
+        .collect()
+}
+
+/// Finds the objects the way the parser used to.
+fn flood_fill_objects(mut image: Vec<Vec<bool>>) -> Vec<Vec<(u32, u32)>> {
+    let (width, height) = (image[0].len() as isize, image.len() as isize);
+    let mut objects = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            if !pixel_value(&image, x, y) {
+                continue;
+            }
+
+            let mut pixels = Vec::new();
+            let mut queue = vec![(x, y)];
+            image[y as usize][x as usize] = false;
+            while let Some((x, y)) = queue.pop() {
+                pixels.push((x as u32, y as u32));
+                for ny in y - 1..y + 2 {
+                    for nx in x - 1..x + 2 {
+                        if pixel_value(&image, nx, ny) {
+                            image[ny as usize][nx as usize] = false;
+                            queue.push((nx, ny));
+                        }
+                    }
+                }
+            }
+            objects.push(pixels);
+        }
+    }
+
+    objects
+}
+
+fn pixel_value(image: &[Vec<bool>], x: isize, y: isize) -> bool {
+    if x < 0 || y < 0 {
+        return false;
+    }
+
+    match image.get(y as usize) {
+        None => false,
+        Some(row) => row.get(x as usize).copied().unwrap_or(false),
+    }
+}
@@ -0,0 +1,48 @@
+//! Times how long it takes to parse each of the test images, from the decoded
+//! image to the board.
+//!
+//! Run with `cargo bench`, or with `cargo bench --features parallel` to use
+//! all cores.
+
+use board_parser::{parse_image, ParserConfig};
+use std::fs;
+use std::time::{Duration, Instant};
+
+const ASSETS_DIR: &str = "assets/test";
+const ITERATIONS: u32 = 5;
+
+fn main() {
+    let mut images: Vec<_> = fs::read_dir(ASSETS_DIR)
+        .expect("Cannot read test assets")
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "jpeg"))
+        .collect();
+    images.sort();
+
+    let config = ParserConfig::default();
+    let mut total = Duration::new(0, 0);
+    for path in images {
+        let image = image::open(&path).expect("Cannot open image").to_rgb();
+
+        let start = Instant::now();
+        let mut parsed = None;
+        for _ in 0..ITERATIONS {
+            parsed = Some(parse_image(image.clone(), &config));
+        }
+        let elapsed = start.elapsed() / ITERATIONS;
+
+        let size = match parsed.expect("The image was parsed") {
+            Ok(board) => format!("{}x{}", board.size(), board.size()),
+            Err(error) => error.to_string(),
+        };
+        println!(
+            "{:?}: {}, {:?}",
+            path.file_name().expect("The path is a file"),
+            size,
+            elapsed,
+        );
+        total += elapsed;
+    }
+
+    println!("total: {:?}", total);
+}
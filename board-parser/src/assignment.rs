@@ -0,0 +1,103 @@
+//! Solves the assignment problem: given a cost of assigning each row to each
+//! column, find an assignment of every row to a different column such that the
+//! total cost is the least possible. This is the Hungarian algorithm in its
+//! `O(n^2 * m)` form for `n` rows and `m` columns.
+
+/// Returns the column assigned to each row. There must be at least as many
+/// columns as there are rows and all rows must have the same length.
+pub(crate) fn min_cost_assignment(costs: &[Vec<f32>]) -> Vec<usize> {
+    let rows = costs.len();
+    if rows == 0 {
+        return Vec::new();
+    }
+    let columns = costs[0].len();
+    assert!(rows <= columns, "Each row needs its own column");
+
+    // Potentials of the rows and columns, and which row is assigned to each
+    // column. Index 0 is a virtual column which the row being added starts
+    // from, hence everything else is shifted by one.
+    let mut row_potential = vec![0.0; rows + 1];
+    let mut column_potential = vec![0.0; columns + 1];
+    let mut column_row = vec![0; columns + 1];
+    let mut way = vec![0; columns + 1];
+
+    for row in 1..=rows {
+        column_row[0] = row;
+        let mut column = 0;
+        let mut min_slack = vec![f32::INFINITY; columns + 1];
+        let mut used = vec![false; columns + 1];
+
+        // Dijkstra-like search for the cheapest augmenting path which ends
+        // in a free column.
+        loop {
+            used[column] = true;
+            let current_row = column_row[column];
+            let mut delta = f32::INFINITY;
+            let mut next_column = 0;
+            for j in 1..=columns {
+                if used[j] {
+                    continue;
+                }
+                let slack = costs[current_row - 1][j - 1]
+                    - row_potential[current_row]
+                    - column_potential[j];
+                if slack < min_slack[j] {
+                    min_slack[j] = slack;
+                    way[j] = column;
+                }
+                if min_slack[j] < delta {
+                    delta = min_slack[j];
+                    next_column = j;
+                }
+            }
+
+            for j in 0..=columns {
+                if used[j] {
+                    row_potential[column_row[j]] += delta;
+                    column_potential[j] -= delta;
+                } else {
+                    min_slack[j] -= delta;
+                }
+            }
+            column = next_column;
+            if column_row[column] == 0 {
+                break;
+            }
+        }
+
+        // Flips the assignments along the path.
+        while column != 0 {
+            let previous = way[column];
+            column_row[column] = column_row[previous];
+            column = previous;
+        }
+    }
+
+    let mut assignment = vec![0; rows];
+    for j in 1..=columns {
+        if column_row[j] != 0 {
+            assignment[column_row[j] - 1] = j - 1;
+        }
+    }
+
+    assignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_min_cost_assignment() {
+        let costs = vec![
+            vec![4.0, 1.0, 3.0, 9.0],
+            vec![2.0, 0.0, 5.0, 9.0],
+            vec![3.0, 2.0, 2.0, 9.0],
+        ];
+
+        // Both of the first two rows prefer the second column, but giving it
+        // to the first one is cheaper overall.
+        assert_eq!(vec![1, 0, 2], min_cost_assignment(&costs));
+        assert_eq!(Vec::<usize>::new(), min_cost_assignment(&[]));
+    }
+}
@@ -0,0 +1,245 @@
+//! Tells whether there's a black stone, a white stone or nothing on an
+//! intersection by looking at the patch of the image around it.
+//!
+//! White stones are hard to find with a fixed threshold, because under warm
+//! light they're about as bright as the board. They do however keep their own
+//! tint while the wooden board is yellow, so each pixel is compared to the
+//! colour of the board right next to the intersection instead.
+
+use crate::board::Point;
+use crate::color::luminance;
+use crate::num_ext::NumExt;
+use score_counter::Stone;
+
+// How large is the disc around the intersection which is classified, relative
+// to the intersection spacing. A bit smaller than a stone, because stones are
+// rarely placed exactly on the intersection.
+const DISC_RADIUS: f32 = 0.25;
+// How large are the patches of the board in the middle of the squares next to
+// the intersection, relative to the intersection spacing.
+const BOARD_PATCH_RADIUS: f32 = 0.1;
+// A pixel is black if it's this many times darker than the board.
+const BLACK_LUMINANCE_RATIO: f32 = 0.45;
+// A pixel is white if it's brighter and bluer than the board by this much in
+// total, see `whiteness`.
+const MIN_WHITENESS: f32 = 0.15;
+// At least this portion of the disc must be covered by a stone of one colour.
+const MIN_STONE_COVERAGE: f32 = 0.4;
+// The radius of a star point relative to the intersection spacing, and how
+// many times darker than the board are the star points and the lines.
+const STAR_POINT_RADIUS: f32 = 0.07;
+const DARK_LUMINANCE_RATIO: f32 = 0.5;
+
+/// Classifies the intersection at given pixel. The spacing is the distance
+/// between two neighbouring intersections in pixels around this one. Returns
+/// the stone along with the confidence in the classification between 0 and 1.
+pub(crate) fn classify_intersection(
+    image: &image::RgbImage,
+    at: Point,
+    spacing: f32,
+) -> (Stone, f32) {
+    let board = match board_color(image, at, spacing) {
+        Some(board) => board,
+        None => return (Stone::None, 0.0),
+    };
+
+    let (mut black, mut white, mut total) = (0, 0, 0);
+    for pixel in disc(image, at, spacing * DISC_RADIUS) {
+        total += 1;
+        if luminance(*pixel) < board.luminance * BLACK_LUMINANCE_RATIO {
+            black += 1;
+        } else if whiteness(pixel, &board) > MIN_WHITENESS {
+            white += 1;
+        }
+    }
+    if total == 0 {
+        return (Stone::None, 0.0);
+    }
+
+    let black = black as f32 / total as f32;
+    let white = white as f32 / total as f32;
+    // The confidence is lowest when the coverage is right at the threshold
+    // and grows as the coverage gets further from it.
+    let confidence = |coverage: f32| {
+        if coverage >= MIN_STONE_COVERAGE {
+            0.5 + 0.5 * (coverage - MIN_STONE_COVERAGE)
+                / (1.0 - MIN_STONE_COVERAGE)
+        } else {
+            0.5 + 0.5 * (MIN_STONE_COVERAGE - coverage) / MIN_STONE_COVERAGE
+        }
+    };
+
+    let (stone, coverage) = if black >= MIN_STONE_COVERAGE && black >= white {
+        (Stone::Black, black)
+    } else if white >= MIN_STONE_COVERAGE {
+        (Stone::White, white)
+    } else {
+        (Stone::None, black.max(white))
+    };
+    // Stones next to the intersection cover some of the patches which the
+    // colour of the board is taken from. If the patches differ, the colour
+    // might be a stone's rather than the board's, and so might the stone.
+    (stone, confidence(coverage) * (1.0 - board.spread).max(0.0))
+}
+
+/// How much of a small disc around the intersection is darker than the board,
+/// between 0 and 1. A star point or a black stone covers all of it, while the
+/// lines only cross it. Returns `None` if the board around the intersection
+/// isn't in the image.
+pub(crate) fn dark_portion(
+    image: &image::RgbImage,
+    at: Point,
+    spacing: f32,
+) -> Option<f32> {
+    let board = board_color(image, at, spacing)?;
+
+    let (mut dark, mut total) = (0, 0);
+    for pixel in disc(image, at, spacing * STAR_POINT_RADIUS) {
+        total += 1;
+        if luminance(*pixel) < board.luminance * DARK_LUMINANCE_RATIO {
+            dark += 1;
+        }
+    }
+    if total == 0 {
+        return None;
+    }
+
+    Some(dark as f32 / total as f32)
+}
+
+/// The colour of the wood around an intersection.
+struct BoardColor {
+    luminance: f32,
+    // Blue to red ratio. The board is yellow, hence the ratio is low.
+    tint: f32,
+    // How much do the patches which the colour was taken from differ in
+    // luminance, relative to it.
+    spread: f32,
+}
+
+/// Samples the middle of the four squares around the intersection. Neither
+/// this nor any neighbouring stone covers them, except for the stones which
+/// are placed off their intersection. The median of the four is therefore the
+/// board.
+fn board_color(
+    image: &image::RgbImage,
+    at: Point,
+    spacing: f32,
+) -> Option<BoardColor> {
+    let offset = spacing / 2.0;
+    let corners = [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)];
+    let mut patches: Vec<_> = corners
+        .iter()
+        .filter_map(|(dx, dy)| {
+            let x = at.x as f32 + dx * offset;
+            let y = at.y as f32 + dy * offset;
+            if x < 0.0 || y < 0.0 {
+                return None;
+            }
+            let center = Point::new(x.round() as u32, y.round() as u32);
+            let pixels: Vec<_> =
+                disc(image, center, spacing * BOARD_PATCH_RADIUS).collect();
+            mean_color(&pixels)
+        })
+        .collect();
+    patches.sort_by(|a, b| a.luminance.partial_ord(b.luminance));
+
+    // With an even number of patches, the two in the middle are averaged.
+    let len = patches.len();
+    let middle = patches.get(len.saturating_sub(1) / 2..=len / 2)?;
+    let luminance =
+        middle.iter().map(|c| c.luminance).sum::<f32>() / middle.len() as f32;
+    let spread = (middle[middle.len() - 1].luminance - middle[0].luminance)
+        / luminance.max(1.0);
+    Some(BoardColor {
+        luminance,
+        tint: middle.iter().map(|c| c.tint).sum::<f32>() / middle.len() as f32,
+        spread,
+    })
+}
+
+fn mean_color(pixels: &[&[u8; 3]]) -> Option<BoardColor> {
+    if pixels.is_empty() {
+        return None;
+    }
+
+    let count = pixels.len() as f32;
+    Some(BoardColor {
+        luminance: pixels.iter().map(|p| luminance(**p)).sum::<f32>() / count,
+        tint: pixels.iter().map(|p| tint(p)).sum::<f32>() / count,
+        spread: 0.0,
+    })
+}
+
+/// All pixels within given distance from the center which are in the image.
+fn disc<'a>(
+    image: &'a image::RgbImage,
+    center: Point,
+    radius: f32,
+) -> impl Iterator<Item = &'a [u8; 3]> + 'a {
+    let (width, height) = image.dimensions();
+    let r = radius.ceil() as i64;
+    let (cx, cy) = (center.x as i64, center.y as i64);
+
+    (cy - r..=cy + r)
+        .flat_map(move |y| (cx - r..=cx + r).map(move |x| (x, y)))
+        .filter(move |(x, y)| {
+            let (dx, dy) = ((x - cx) as f32, (y - cy) as f32);
+            *x >= 0
+                && *y >= 0
+                && *x < width as i64
+                && *y < height as i64
+                && dx * dx + dy * dy <= radius * radius
+        })
+        .map(move |(x, y)| &image.get_pixel(x as u32, y as u32).0)
+}
+
+fn tint([r, _, b]: &[u8; 3]) -> f32 {
+    (*b as f32 + 1.0) / (*r as f32 + 1.0)
+}
+
+/// White stones are brighter than the board and they're also less yellow. On
+/// its own neither is reliable: in daylight the stones are as bright as the
+/// board, and under a warm light they're yellow too.
+fn whiteness(pixel: &[u8; 3], board: &BoardColor) -> f32 {
+    let brightness = luminance(*pixel) / board.luminance.max(1.0) - 1.0;
+    brightness + tint(pixel) - board.tint
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASSETS_DIR: &str = "assets/test";
+
+    fn open_image(name: &str) -> image::RgbImage {
+        image::open(format!("{}/{}.jpeg", ASSETS_DIR, name))
+            .expect("Cannot open image")
+            .to_rgb()
+    }
+
+    #[test]
+    fn test_classify_in_daylight() {
+        let image = open_image("test2");
+        let classify =
+            |x, y| classify_intersection(&image, Point::new(x, y), 50.0).0;
+
+        assert_eq!(Stone::White, classify(566, 653));
+        assert_eq!(Stone::White, classify(226, 757));
+        assert_eq!(Stone::Black, classify(371, 705));
+        assert_eq!(Stone::None, classify(129, 497));
+        assert_eq!(Stone::None, classify(371, 757));
+    }
+
+    #[test]
+    fn test_classify_under_warm_light() {
+        let image = open_image("test7");
+        let classify =
+            |x, y| classify_intersection(&image, Point::new(x, y), 32.0).0;
+
+        assert_eq!(Stone::White, classify(455, 608));
+        assert_eq!(Stone::Black, classify(465, 815));
+        assert_eq!(Stone::None, classify(203, 540));
+        assert_eq!(Stone::None, classify(266, 607));
+    }
+}
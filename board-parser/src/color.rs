@@ -0,0 +1,7 @@
+//! Colour helpers which the stages of the parser share.
+
+/// How bright does the pixel look, by the Rec. 601 weights of its red, green
+/// and blue channels.
+pub(crate) fn luminance<T: Into<f32> + Copy>([r, g, b]: [T; 3]) -> f32 {
+    0.299 * r.into() + 0.587 * g.into() + 0.114 * b.into()
+}
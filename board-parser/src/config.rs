@@ -0,0 +1,131 @@
+//! Parameters of the stone detection which depend on the stones and the
+//! camera. Slate and shell stones are matte while glass and plastic ones
+//! reflect the light, and some stone sets are smaller than others.
+
+/// How the parser tells black stones in the image. The defaults work for
+/// glass stones photographed by a phone.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParserConfig {
+    pub(crate) black_threshold: f32,
+    pub(crate) black_to_local_mean: f32,
+    pub(crate) grayness_limit: f32,
+    pub(crate) min_stone_size: f32,
+    pub(crate) min_black_stones: usize,
+    pub(crate) min_size_ratio: f32,
+    pub(crate) max_size_ratio: f32,
+    pub(crate) detection_size: u32,
+}
+
+impl Default for ParserConfig {
+    fn default() -> Self {
+        Self {
+            black_threshold: 30.0,
+            black_to_local_mean: 0.12,
+            grayness_limit: 10.0,
+            min_stone_size: 8.0,
+            min_black_stones: 6,
+            min_size_ratio: 0.66,
+            max_size_ratio: 1.5,
+            detection_size: 1600,
+        }
+    }
+}
+
+impl ParserConfig {
+    /// Starts from the default parameters.
+    pub fn builder() -> ParserConfigBuilder {
+        ParserConfigBuilder {
+            config: Self::default(),
+        }
+    }
+}
+
+/// Changes some of the parameters of the parser, see `ParserConfig::builder`.
+#[derive(Clone, Debug)]
+pub struct ParserConfigBuilder {
+    config: ParserConfig,
+}
+
+impl ParserConfigBuilder {
+    /// No pixel with higher luminance, between 0 and 255, is black. The image
+    /// is white balanced first. Raise it for stones with bright reflections.
+    pub fn black_threshold(mut self, luminance: f32) -> Self {
+        self.config.black_threshold = luminance;
+        self
+    }
+
+    /// A black pixel is at most this many times as bright as the mean of the
+    /// pixels around it. Raise it for dim photos.
+    pub fn black_to_local_mean(mut self, ratio: f32) -> Self {
+        self.config.black_to_local_mean = ratio;
+        self
+    }
+
+    /// How much can the colour channels of a black pixel differ after the
+    /// image is white balanced. Raise it for stones which aren't neutral,
+    /// e.g. greenish yunzi.
+    pub fn grayness_limit(mut self, limit: f32) -> Self {
+        self.config.grayness_limit = limit;
+        self
+    }
+
+    /// Black objects narrower or lower than this many pixels are noise.
+    pub fn min_stone_size(mut self, pixels: f32) -> Self {
+        self.config.min_stone_size = pixels;
+        self
+    }
+
+    /// The board can only be found from the stones if there are at least this
+    /// many black ones, otherwise its grid lines are needed.
+    pub fn min_black_stones(mut self, count: usize) -> Self {
+        self.config.min_black_stones = count;
+        self
+    }
+
+    /// The ellipse of a stone is at least `min` and at most `max` times as
+    /// wide as it's long, and the width and the height of the stone are in the
+    /// same range relative to the typical stone.
+    /// Widen it for photos taken from a low angle, in which the stones are
+    /// flattened.
+    pub fn size_ratio(mut self, min: f32, max: f32) -> Self {
+        self.config.min_size_ratio = min;
+        self.config.max_size_ratio = max;
+        self
+    }
+
+    /// The board is found in the image halved as many times as it takes for
+    /// its larger side to be at most this many pixels, and only then refined
+    /// in the image itself. Lower it to parse large photos faster, as long as
+    /// the stones stay at least a dozen pixels large.
+    pub fn detection_size(mut self, pixels: u32) -> Self {
+        self.config.detection_size = pixels;
+        self
+    }
+
+    pub fn build(self) -> ParserConfig {
+        self.config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_config() {
+        assert_eq!(ParserConfig::default(), ParserConfig::builder().build());
+
+        let config = ParserConfig::builder()
+            .grayness_limit(16.0)
+            .min_black_stones(10)
+            .size_ratio(0.5, 2.0)
+            .build();
+        assert_eq!(16.0, config.grayness_limit);
+        assert_eq!(10, config.min_black_stones);
+        assert_eq!((0.5, 2.0), (config.min_size_ratio, config.max_size_ratio));
+        assert_eq!(
+            ParserConfig::default().black_threshold,
+            config.black_threshold
+        );
+    }
+}
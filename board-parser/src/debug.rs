@@ -1,23 +1,16 @@
 use super::board::tests::*;
-use crate::num_ext::*;
 use std::collections::HashMap;
 
 #[allow(dead_code)]
 pub(crate) fn pixels(black_pixels: &BlackPixels) {
     let mut image = image::DynamicImage::new_luma8(
-        black_pixels[0].len() as u32,
-        black_pixels.len() as u32,
+        black_pixels.width(),
+        black_pixels.height(),
     );
     let gray_image = image.as_mut_luma8().unwrap();
 
     for (x, y, pixel) in gray_image.enumerate_pixels_mut() {
-        let is_pixel_black = black_pixels
-            .get(y as usize)
-            .unwrap()
-            .get(x as usize)
-            .unwrap();
-
-        if *is_pixel_black {
+        if black_pixels.get(x, y) {
             pixel.0 = [0];
         } else {
             pixel.0 = [255];
@@ -61,7 +54,7 @@ pub(crate) fn board(
     let gray_image = image.as_mut_luma8().unwrap();
 
     // Draws the fields.
-    for (_, stone) in stones {
+    for stone in stones.values() {
         for y in (stone.y - field_radius)..(stone.y + field_radius) {
             for x in (stone.x - field_radius)..(stone.x + field_radius) {
                 let pixel = gray_image.get_pixel_mut(x, y);
@@ -72,8 +65,8 @@ pub(crate) fn board(
 
     // Draws the lines.
     for (x, y, pixel) in gray_image.enumerate_pixels_mut() {
-        let diff_x = x.diff(center.x) as f32;
-        let diff_y = y.diff(center.y) as f32;
+        let diff_x = (x as f32 - center.x as f32).abs();
+        let diff_y = (y as f32 - center.y as f32).abs();
         let div_x = diff_x / field_size;
         let div_y = diff_y / field_size;
         if div_x.fract() < 0.05 || div_y.fract() < 0.05 {
@@ -0,0 +1,346 @@
+//! A projective transformation of the plane. A photo of a flat board taken
+//! from any angle is the board lattice transformed by a homography, therefore
+//! unlike an affine transformation it also captures that the far side of the
+//! board is smaller than the near one.
+//!
+//! The transformation is a 3x3 matrix `H` which maps a point `(x, y)` to
+//! `(u / w, v / w)` where `(u, v, w) = H * (x, y, 1)`. The matrix is only
+//! defined up to scale, hence the bottom right element is fixed to 1 and there
+//! are 8 parameters.
+
+use crate::board::XYTuple;
+use crate::num_ext::NumExt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Homography {
+    matrix: [[f32; 3]; 3],
+}
+
+impl Homography {
+    /// Maps translated, rotated and scaled lattice onto the image. The
+    /// rotation is in radians.
+    pub(crate) fn similarity(
+        translation: XYTuple,
+        rotation: f32,
+        scale: f32,
+    ) -> Self {
+        let (sin, cos) = rotation.sin_cos();
+        Self {
+            matrix: [
+                [scale * cos, -scale * sin, translation.x],
+                [scale * sin, scale * cos, translation.y],
+                [0.0, 0.0, 1.0],
+            ],
+        }
+    }
+
+    /// Finds the homography which maps each of the first points onto its
+    /// second point with the least squared error. Returns `None` if there are
+    /// fewer than four pairs or if they don't determine the transformation,
+    /// e.g. because most of them lie on one line.
+    ///
+    /// The points are first moved and scaled so that they're centered at the
+    /// origin with average distance `sqrt(2)` from it, otherwise the equations
+    /// mix values in thousands with ones and the solution is imprecise.
+    pub(crate) fn fit(pairs: &[(XYTuple, XYTuple)]) -> Option<Self> {
+        if pairs.len() < 4 {
+            return None;
+        }
+
+        let from = normalization(pairs.iter().map(|(from, _)| *from))?;
+        let to = normalization(pairs.iter().map(|(_, to)| *to))?;
+
+        // Each pair gives two linear equations for the 8 unknown elements of
+        // the matrix, which are solved in the least squares sense.
+        let mut normal = [[0.0f64; 8]; 8];
+        let mut rhs = [0.0f64; 8];
+        for (a, b) in pairs {
+            let a = from.apply_f64(*a);
+            let b = to.apply_f64(*b);
+            let equations = [
+                ([a.0, a.1, 1.0, 0.0, 0.0, 0.0, -b.0 * a.0, -b.0 * a.1], b.0),
+                ([0.0, 0.0, 0.0, a.0, a.1, 1.0, -b.1 * a.0, -b.1 * a.1], b.1),
+            ];
+            for (row, value) in &equations {
+                for i in 0..8 {
+                    for j in 0..8 {
+                        normal[i][j] += row[i] * row[j];
+                    }
+                    rhs[i] += row[i] * value;
+                }
+            }
+        }
+
+        let h = solve(normal, rhs)?;
+        let normalized = Self::from_f64([
+            [h[0], h[1], h[2]],
+            [h[3], h[4], h[5]],
+            [h[6], h[7], 1.0],
+        ])?;
+
+        // Undoes the normalization of both sets of points.
+        let homography = to.inverse()?.compose(&normalized)?.compose(&from)?;
+        if homography.matrix.iter().flatten().all(|e| e.is_finite()) {
+            Some(homography)
+        } else {
+            None
+        }
+    }
+
+    /// Transforms given point. Points which are mapped to infinity, that is on
+    /// the horizon of the board plane, become infinite.
+    pub(crate) fn apply(&self, point: XYTuple) -> XYTuple {
+        let (x, y) = self.apply_f64(point);
+        XYTuple {
+            x: x as f32,
+            y: y as f32,
+        }
+    }
+
+    /// The distance in pixels from the point of the lattice to the next points
+    /// in both directions, on average. That's the intersection spacing, which
+    /// changes across the image because of the perspective.
+    pub(crate) fn spacing_at(&self, point: XYTuple) -> f32 {
+        let center = self.apply(point);
+        let distance = |dx: f32, dy: f32| {
+            let to = self.apply(XYTuple {
+                x: point.x + dx,
+                y: point.y + dy,
+            });
+            (to.x - center.x).hypot(to.y - center.y)
+        };
+
+        (distance(1.0, 0.0) + distance(0.0, 1.0)) / 2.0
+    }
+
+    /// The transformation which maps the points back.
+    pub(crate) fn inverse(&self) -> Option<Self> {
+        let m = self.to_f64();
+        let cofactor = |r1: usize, r2: usize, c1: usize, c2: usize| {
+            m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]
+        };
+        let adjugate = [
+            [
+                cofactor(1, 2, 1, 2),
+                -cofactor(0, 2, 1, 2),
+                cofactor(0, 1, 1, 2),
+            ],
+            [
+                -cofactor(1, 2, 0, 2),
+                cofactor(0, 2, 0, 2),
+                -cofactor(0, 1, 0, 2),
+            ],
+            [
+                cofactor(1, 2, 0, 1),
+                -cofactor(0, 2, 0, 1),
+                cofactor(0, 1, 0, 1),
+            ],
+        ];
+        let determinant = m[0][0] * adjugate[0][0]
+            + m[0][1] * adjugate[1][0]
+            + m[0][2] * adjugate[2][0];
+        if determinant.abs() < f64::EPSILON {
+            return None;
+        }
+
+        let mut inverse = [[0.0; 3]; 3];
+        for (row, adjugate_row) in inverse.iter_mut().zip(&adjugate) {
+            for (e, a) in row.iter_mut().zip(adjugate_row) {
+                *e = a / determinant;
+            }
+        }
+        Self::from_f64(inverse)
+    }
+
+    /// The transformation which first applies the other one and then this one.
+    /// Returns `None` if the product maps the origin to infinity, see
+    /// `from_f64`.
+    pub(crate) fn compose(&self, other: &Self) -> Option<Self> {
+        let (a, b) = (self.to_f64(), other.to_f64());
+        let mut product = [[0.0; 3]; 3];
+        for (i, row) in product.iter_mut().enumerate() {
+            for (j, e) in row.iter_mut().enumerate() {
+                *e = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+            }
+        }
+        Self::from_f64(product)
+    }
+
+    fn apply_f64(&self, point: XYTuple) -> (f64, f64) {
+        let m = self.to_f64();
+        let (x, y) = (point.x as f64, point.y as f64);
+        let w = m[2][0] * x + m[2][1] * y + m[2][2];
+        (
+            (m[0][0] * x + m[0][1] * y + m[0][2]) / w,
+            (m[1][0] * x + m[1][1] * y + m[1][2]) / w,
+        )
+    }
+
+    // Scales the matrix so that the bottom right element is 1. Matrices whose
+    // bottom right element is zero can't be scaled so, and they're degenerate
+    // for a photo of a board anyway.
+    fn from_f64(matrix: [[f64; 3]; 3]) -> Option<Self> {
+        let scale = matrix[2][2];
+        if scale.abs() < f64::EPSILON {
+            return None;
+        }
+
+        let mut result = [[0.0; 3]; 3];
+        for (row, matrix_row) in result.iter_mut().zip(&matrix) {
+            for (e, m) in row.iter_mut().zip(matrix_row) {
+                *e = (m / scale) as f32;
+            }
+        }
+        Some(Self { matrix: result })
+    }
+
+    fn to_f64(&self) -> [[f64; 3]; 3] {
+        let mut result = [[0.0; 3]; 3];
+        for (row, matrix_row) in result.iter_mut().zip(&self.matrix) {
+            for (e, m) in row.iter_mut().zip(matrix_row) {
+                *e = *m as f64;
+            }
+        }
+        result
+    }
+}
+
+// Moves the centroid of the points to the origin and scales them so that
+// their average distance to it is `sqrt(2)`.
+fn normalization(points: impl Iterator<Item = XYTuple>) -> Option<Homography> {
+    let points: Vec<_> = points.collect();
+    let count = points.len() as f32;
+    let centroid = XYTuple {
+        x: points.iter().map(|p| p.x).sum::<f32>() / count,
+        y: points.iter().map(|p| p.y).sum::<f32>() / count,
+    };
+    let mean_distance = points
+        .iter()
+        .map(|p| {
+            ((p.x - centroid.x).powi(2) + (p.y - centroid.y).powi(2)).sqrt()
+        })
+        .sum::<f32>()
+        / count;
+    if mean_distance <= f32::EPSILON {
+        return None;
+    }
+
+    let scale = 2f32.sqrt() / mean_distance;
+    Some(Homography::similarity(
+        XYTuple {
+            x: -centroid.x * scale,
+            y: -centroid.y * scale,
+        },
+        0.0,
+        scale,
+    ))
+}
+
+// Solves the system of linear equations by Gaussian elimination with partial
+// pivoting. Returns `None` if the matrix is singular.
+fn solve(mut matrix: [[f64; 8]; 8], mut rhs: [f64; 8]) -> Option<[f64; 8]> {
+    const SINGULAR_PIVOT: f64 = 1e-10;
+
+    for column in 0..8 {
+        let pivot = (column..8).max_by(|a, b| {
+            matrix[*a][column]
+                .abs()
+                .partial_ord(matrix[*b][column].abs())
+        })?;
+        if matrix[pivot][column].abs() < SINGULAR_PIVOT {
+            return None;
+        }
+        matrix.swap(column, pivot);
+        rhs.swap(column, pivot);
+
+        for row in column + 1..8 {
+            let factor = matrix[row][column] / matrix[column][column];
+            let pivot_row = matrix[column];
+            for (e, pivot) in
+                matrix[row].iter_mut().zip(&pivot_row).skip(column)
+            {
+                *e -= factor * pivot;
+            }
+            rhs[row] -= factor * rhs[column];
+        }
+    }
+
+    let mut solution = [0.0; 8];
+    for row in (0..8).rev() {
+        let known: f64 =
+            (row + 1..8).map(|k| matrix[row][k] * solution[k]).sum();
+        solution[row] = (rhs[row] - known) / matrix[row][row];
+    }
+    Some(solution)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xy(x: f32, y: f32) -> XYTuple {
+        XYTuple { x, y }
+    }
+
+    fn assert_close(expected: XYTuple, actual: XYTuple) {
+        assert!(
+            (expected.x - actual.x).abs() < 1e-2
+                && (expected.y - actual.y).abs() < 1e-2,
+            "expected {:?}, got {:?}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_fit_homography() {
+        // The board is seen from below, so the rows further away are shorter
+        // and closer to each other. The lattice is also rotated.
+        let truth = Homography {
+            matrix: [
+                [48.0, -9.0, 400.0],
+                [7.0, 30.0, 600.0],
+                [0.0, -0.03, 1.0],
+            ],
+        };
+        let pairs: Vec<_> = (-6..=6)
+            .flat_map(|column| (-6..=6).map(move |row| (column, row)))
+            .step_by(5)
+            .map(|(column, row)| {
+                let at = xy(column as f32, row as f32);
+                (at, truth.apply(at))
+            })
+            .collect();
+
+        let fitted = Homography::fit(&pairs).expect("The lattice is regular");
+        for (lattice, image) in &pairs {
+            assert_close(*image, fitted.apply(*lattice));
+        }
+        let inverse = fitted.inverse().expect("The fit is invertible");
+        for (lattice, image) in &pairs {
+            assert_close(*lattice, inverse.apply(*image));
+        }
+    }
+
+    #[test]
+    fn test_fit_degenerate_points() {
+        let on_line: Vec<_> = (0..6)
+            .map(|i| (xy(i as f32, 0.0), xy(10.0 * i as f32, 5.0)))
+            .collect();
+        assert_eq!(None, Homography::fit(&on_line));
+        assert_eq!(None, Homography::fit(&on_line[..3]));
+    }
+
+    #[test]
+    fn test_compose_to_infinity() {
+        // The horizon of the first transformation is the line `x = -1`, onto
+        // which the second one moves the origin.
+        let horizon = Homography {
+            matrix: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [1.0, 0.0, 1.0]],
+        };
+        let shift = Homography::similarity(xy(-1.0, 0.0), 0.0, 1.0);
+
+        assert_eq!(None, horizon.compose(&shift));
+        assert!(shift.compose(&horizon).is_some());
+    }
+}
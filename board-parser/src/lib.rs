@@ -1,25 +1,561 @@
+mod assignment;
 mod board;
+mod classify;
+mod color;
+mod config;
 #[cfg(test)]
 mod debug;
+mod homography;
+mod lines;
+mod mask;
 mod num_ext;
+mod parallel;
+mod pyramid;
+mod size;
+mod threshold;
 
-pub fn parse_image(image: image::RgbImage) {
-    let board_map = board::board_map(&image);
-    println!("Here's the board map: {:#?}", board_map);
+pub use board::Point;
+use board::XYTuple;
+pub use config::{ParserConfig, ParserConfigBuilder};
+use homography::Homography;
+use score_counter::{Board, Grid, Stone};
+use std::convert::TryFrom;
+use std::fmt;
+
+// An intersection which a black stone couldn't be placed on is at most this
+// likely to be classified right.
+const UNPLACED_STONE_CONFIDENCE: f32 = 0.5;
+
+/// A go board recognized in an image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParsedBoard {
+    /// The stone on each intersection.
+    pub board: Board<Stone>,
+    /// Pixel of the image at which each intersection is, indexed by
+    /// `(row, column)`.
+    pub intersections: Grid<Point>,
+    /// How sure is the parser about the size of the board, between 0 and 1.
+    pub size_confidence: f32,
+    /// Pixels of the corners of the board in the order top left, top right,
+    /// bottom right and bottom left. They're where the outermost grid lines
+    /// cross if those were found, and the corner intersections otherwise.
+    pub corners: [Point; 4],
+    /// How sure is the parser about the stone on each intersection, between
+    /// 0 and 1.
+    pub confidence: Grid<f32>,
+    /// How far is what was found of each intersection in the image from where
+    /// the fitted lattice puts it, relative to the intersection spacing. That's
+    /// the black stone on the intersection if there's one, and the grid lines
+    /// next to it otherwise. Intersections with neither, e.g. because white
+    /// stones cover the lines, get the mean residual of the others.
+    pub residuals: Grid<f32>,
+}
+
+impl ParsedBoard {
+    /// How many lines does the board have in each direction.
+    pub fn size(&self) -> usize {
+        self.board.size()
+    }
+
+    /// The `(row, column)` of each intersection which the parser is less sure
+    /// about than the given confidence, row by row. These are the ones to ask
+    /// the user to confirm.
+    pub fn ambiguous(&self, min_confidence: f32) -> Vec<(usize, usize)> {
+        self.confidence
+            .iter()
+            .filter(|(_, confidence)| **confidence < min_confidence)
+            .map(|(at, _)| at)
+            .collect()
+    }
+}
+
+/// Reasons why a board couldn't be recognized in an image.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ParseError {
+    /// The board is located by the black stones on it, therefore there must be
+    /// at least a few of them.
+    TooFewBlackStones { found: usize, required: usize },
+    /// The stones don't lie on intersections of any lattice.
+    NoLatticeFit,
+    /// Part of the board is outside of the image.
+    BoardCutOff,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::TooFewBlackStones { found, required } => write!(
+                f,
+                "Found {} black stones, but at least {} are required",
+                found, required
+            ),
+            ParseError::NoLatticeFit => {
+                write!(f, "The stones aren't placed on a board lattice")
+            }
+            ParseError::BoardCutOff => {
+                write!(f, "The board isn't entirely in the image")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Finds a go board in the image and tells which stone is on each of its
+/// intersections. Use `ParserConfig::default()` unless the stones or the
+/// camera are unusual.
+pub fn parse_image(
+    image: image::RgbImage,
+    config: &ParserConfig,
+) -> Result<ParsedBoard, ParseError> {
+    let located = pyramid::locate_board(&image, config)?;
+    let size = located.size;
+
+    let mut intersections = Grid::new(size, Point::new(0, 0));
+    for row in 0..size {
+        for column in 0..size {
+            intersections[(row, column)] = pixel_in_image(
+                &image,
+                located.homography.apply(XYTuple {
+                    x: column as f32,
+                    y: row as f32,
+                }),
+            )?;
+        }
+    }
+
+    let last = size - 1;
+    let corners = match located.corners {
+        Some(corners) => {
+            let mut pixels = [Point::new(0, 0); 4];
+            for (pixel, corner) in pixels.iter_mut().zip(&corners) {
+                *pixel = pixel_in_image(&image, *corner)?;
+            }
+            pixels
+        }
+        None => [
+            intersections[(0, 0)],
+            intersections[(0, last)],
+            intersections[(last, last)],
+            intersections[(last, 0)],
+        ],
+    };
+
+    let mut stone_grid = Grid::new(size, Stone::None);
+    let mut confidence = Grid::new(size, 0.0);
+    for ((row, column), point) in intersections.iter() {
+        let spacing = located.homography.spacing_at(XYTuple {
+            x: column as f32,
+            y: row as f32,
+        });
+        let (stone, stone_confidence) =
+            classify::classify_intersection(&image, *point, spacing);
+        stone_grid[(row, column)] = stone;
+        confidence[(row, column)] = stone_confidence;
+    }
+
+    // Black stones which were placed on the lattice are certain, only their
+    // distance to the intersection lowers the confidence.
+    let to_board = located
+        .homography
+        .inverse()
+        .ok_or(ParseError::NoLatticeFit)?;
+    let mut residuals = Grid::new(size, None);
+    for (&at, stone) in &located.stones {
+        let (row, column) = at;
+        let position = to_board.apply(*stone);
+        let residual =
+            (position.x - column as f32).hypot(position.y - row as f32);
+        stone_grid[at] = Stone::Black;
+        confidence[at] = placed_stone_confidence(residual);
+        residuals[at] = Some(residual);
+    }
+
+    // A black stone which couldn't be placed is next to a stone which took
+    // its intersection, hence either of them might be on the wrong one.
+    for stone in &located.unplaced {
+        let position = to_board.apply(*stone);
+        let (row, column) = (position.y.round(), position.x.round());
+        if row < 0.0 || column < 0.0 {
+            continue;
+        }
+        let at = (row as usize, column as usize);
+        if !confidence.contains(at) {
+            continue;
+        }
+
+        let residual = (position.x - column).hypot(position.y - row);
+        confidence[at] = confidence[at].min(UNPLACED_STONE_CONFIDENCE);
+        residuals[at].get_or_insert(residual);
+    }
+    let residuals = fill_residuals(residuals, &located.line_points, &to_board);
+
+    let board = Board::try_from(stone_grid).expect("The size is standard");
+    Ok(ParsedBoard {
+        board,
+        intersections,
+        size_confidence: located.size_confidence,
+        corners,
+        confidence,
+        residuals,
+    })
+}
+
+/// Fills in the residual of each intersection without a black stone from the
+/// points on the grid lines next to it, see `ParsedBoard::residuals`. The
+/// homography maps the image onto the lattice.
+fn fill_residuals(
+    stone_residuals: Grid<Option<f32>>,
+    line_points: &[(XYTuple, XYTuple)],
+    to_board: &Homography,
+) -> Grid<f32> {
+    let mut line_residuals = Grid::new(stone_residuals.size(), (0.0, 0));
+    for (at, found) in line_points {
+        let position = to_board.apply(*found);
+        let residual = (position.x - at.x).hypot(position.y - at.y);
+        // The point is midway between two intersections on its line.
+        let (dx, dy) = if at.x.fract() == 0.0 {
+            (0.0, 0.5)
+        } else {
+            (0.5, 0.0)
+        };
+        for (x, y) in &[(at.x - dx, at.y - dy), (at.x + dx, at.y + dy)] {
+            let intersection = (y.round() as usize, x.round() as usize);
+            if let Some((sum, count)) = line_residuals.get_mut(intersection) {
+                *sum += residual;
+                *count += 1;
+            }
+        }
+    }
+
+    let mut residuals = stone_residuals;
+    for (at, (sum, count)) in line_residuals.iter() {
+        if residuals[at].is_none() && *count > 0 {
+            residuals[at] = Some(sum / *count as f32);
+        }
+    }
+    let found: Vec<_> = residuals.iter().filter_map(|(_, r)| *r).collect();
+    let mean = found.iter().sum::<f32>() / found.len().max(1) as f32;
+
+    residuals.map(|residual| residual.unwrap_or(mean))
+}
+
+/// How sure is the parser about a black stone which was placed on an
+/// intersection given its residual. A stone midway between four intersections,
+/// a half of the diagonal away from each, could be on any of them. The
+/// assignment can place a stone on any corner of the square it's in, hence up
+/// to the whole diagonal away, and the confidence in such a stone is 0.
+fn placed_stone_confidence(residual: f32) -> f32 {
+    (1.0 - residual.powi(2) * 2.0).max(0.0)
+}
+
+/// Finds the objects which the black pixels of the grid indexed by `[y][x]`
+/// make up, the way the parser does, and returns the pixels `(x, y)` of each.
+/// Public only for the benchmarks, which compare it with a flood fill.
+#[doc(hidden)]
+pub fn label_black_objects(black: &[Vec<bool>]) -> Vec<Vec<(u32, u32)>> {
+    let width = black.first().map_or(0, Vec::len) as u32;
+    let mask = mask::BitMask::from_fn(width, black.len() as u32, |x, y| {
+        black[y as usize][x as usize]
+    });
+
+    mask::objects(&mask)
+        .into_iter()
+        .map(|runs| {
+            runs.into_iter()
+                .flat_map(|run| run.pixels())
+                .map(|pixel| (pixel.x, pixel.y))
+                .collect()
+        })
+        .collect()
+}
+
+/// The pixel at given position, or an error if it's outside of the image.
+fn pixel_in_image(
+    image: &image::RgbImage,
+    at: XYTuple,
+) -> Result<Point, ParseError> {
+    let (width, height) = image.dimensions();
+    if !(0.0..width as f32).contains(&at.x)
+        || !(0.0..height as f32).contains(&at.y)
+    {
+        return Err(ParseError::BoardCutOff);
+    }
+
+    Ok(Point::new(at.x.round() as u32, at.y.round() as u32))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::num_ext::NumExt;
+    use std::fs;
     use std::path::Path;
 
     const ASSETS_DIR: &str = "assets/test";
 
+    fn open_image(name: &str) -> image::RgbImage {
+        let assets = &Path::new(ASSETS_DIR);
+        image::open(assets.join(name))
+            .expect("Cannot open image")
+            .to_rgb()
+    }
+
     #[test]
     fn development_test() {
-        let assets = &Path::new(ASSETS_DIR);
-        let image =
-            image::open(assets.join("test10.jpeg")).expect("Cannot open image");
-        board::board_map(&image.to_rgb());
+        assert!(board::board_map(
+            &open_image("test10.jpeg"),
+            &ParserConfig::default()
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_parse_image() {
+        let parsed =
+            parse_image(open_image("test4.jpeg"), &ParserConfig::default())
+                .expect("Board is found");
+
+        assert_eq!(13, parsed.size());
+        assert!(parsed.size_confidence > 0.9);
+        let stones = Grid::from(parsed.board);
+        let count =
+            |color| stones.iter().filter(|(_, stone)| **stone == color).count();
+        assert!(count(Stone::Black) > 10);
+        assert!(count(Stone::White) > 10);
+    }
+
+    #[test]
+    fn test_parse_image_at_lower_resolution() {
+        // The board is found in the image halved, and then refined in the
+        // image itself.
+        let full =
+            parse_image(open_image("test4.jpeg"), &ParserConfig::default())
+                .expect("Board is found");
+        let config = ParserConfig::builder().detection_size(800).build();
+        let halved = parse_image(open_image("test4.jpeg"), &config)
+            .expect("Board is found");
+
+        assert_eq!(full.size(), halved.size());
+        let (full_stones, halved_stones) =
+            (Grid::from(full.board), Grid::from(halved.board));
+        for ((at, stone), (_, halved_stone)) in
+            full_stones.iter().zip(halved_stones.iter())
+        {
+            if *stone == Stone::Black {
+                assert_eq!(Stone::Black, *halved_stone, "at {:?}", at);
+            }
+        }
+
+        // The board is about square in the image, hence the spacing is
+        // about the same everywhere.
+        let [top_left, top_right, ..] = full.corners;
+        let spacing = (top_right.x as f32 - top_left.x as f32)
+            .hypot(top_right.y as f32 - top_left.y as f32)
+            / (full_stones.size() - 1) as f32;
+        for ((_, a), (_, b)) in
+            full.intersections.iter().zip(halved.intersections.iter())
+        {
+            let distance =
+                (a.x as f32 - b.x as f32).hypot(a.y as f32 - b.y as f32);
+            assert!(distance < spacing * 0.1, "{:?} is far from {:?}", b, a);
+        }
+    }
+
+    #[test]
+    fn test_ambiguous_intersections() {
+        // Each of the few intersections which are classified wrong is among
+        // the ones which the user is asked to confirm.
+        for test in &[
+            "test1", "test2", "test3", "test4", "test5", "test6", "test7",
+            "test8",
+        ] {
+            let parsed = parse_image(
+                open_image(&format!("{}.jpeg", test)),
+                &ParserConfig::default(),
+            )
+            .expect("Board is found");
+            let expected = fs::read_to_string(
+                Path::new(ASSETS_DIR).join(format!("{}.txt", test)),
+            )
+            .expect("Cannot read expected board");
+            let expected: Vec<Vec<_>> = expected
+                .lines()
+                .map(|line| line.chars().collect())
+                .collect();
+
+            let ambiguous = parsed.ambiguous(0.8);
+            assert!(
+                ambiguous.len() < 20,
+                "{} are ambiguous in {}",
+                ambiguous.len(),
+                test
+            );
+            let stones = Grid::from(parsed.board.clone());
+            for ((row, column), stone) in stones.iter() {
+                let expected = match expected[row][column] {
+                    '0' => Stone::Black,
+                    '1' => Stone::White,
+                    _ => Stone::None,
+                };
+                if *stone != expected {
+                    assert!(
+                        ambiguous.contains(&(row, column)),
+                        "{:?} at {:?} in {} is {:?} with confidence {}",
+                        stone,
+                        (row, column),
+                        test,
+                        expected,
+                        parsed.confidence[(row, column)]
+                    );
+                }
+            }
+
+            assert!(parsed.ambiguous(0.0).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_residuals() {
+        let parsed =
+            parse_image(open_image("test4.jpeg"), &ParserConfig::default())
+                .expect("Board is found");
+
+        // Most intersections are empty, and the lattice was fitted to the
+        // grid lines next to them.
+        let mut residuals: Vec<_> =
+            parsed.residuals.iter().map(|(_, r)| *r).collect();
+        for (at, residual) in parsed.residuals.iter() {
+            assert!(*residual < 0.5, "{} at {:?}", residual, at);
+        }
+        residuals.sort_by(|a, b| a.partial_ord(*b));
+        let median = residuals[residuals.len() / 2];
+        assert!(median < 0.05, "The median residual is {}", median);
+    }
+
+    #[test]
+    fn test_fill_residuals() {
+        let mut stone_residuals = Grid::new(3, None);
+        stone_residuals[(0, 0)] = Some(0.3);
+        // The column 1 between the rows 0 and 1, and the row 1 between the
+        // columns 1 and 2, both found off by a tenth of the spacing.
+        let line_points = vec![
+            (XYTuple { x: 1.0, y: 0.5 }, XYTuple { x: 1.1, y: 0.5 }),
+            (XYTuple { x: 1.5, y: 1.0 }, XYTuple { x: 1.5, y: 1.1 }),
+        ];
+        let identity =
+            Homography::similarity(XYTuple { x: 0.0, y: 0.0 }, 0.0, 1.0);
+
+        let residuals =
+            fill_residuals(stone_residuals, &line_points, &identity);
+        assert_eq!(0.3, residuals[(0, 0)]);
+        for at in &[(0, 1), (1, 1), (1, 2)] {
+            assert!((residuals[*at] - 0.1).abs() < 1e-5, "{:?}", at);
+        }
+        // The rest gets the mean of the four intersections above.
+        assert!((residuals[(2, 2)] - 0.15).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_placed_stone_confidence() {
+        // The stones are placed on the intersection (0, 0), but they're
+        // found on the diagonal towards the intersection (1, 1).
+        for offset in &[0.0, 0.25, 0.5, 0.75, 1.0] {
+            let residual = (offset * offset * 2.0f32).sqrt();
+            let confidence = placed_stone_confidence(residual);
+            assert!(
+                (0.0..=1.0).contains(&confidence),
+                "{} at {}",
+                confidence,
+                residual
+            );
+        }
+        assert_eq!(1.0, placed_stone_confidence(0.0));
+        assert_eq!(0.0, placed_stone_confidence(2.0f32.sqrt()));
+    }
+
+    #[test]
+    fn test_parse_sparse_board() {
+        // There are only four black stones, so the board is found by its
+        // grid lines.
+        let parsed =
+            parse_image(open_image("test1.jpeg"), &ParserConfig::default())
+                .expect("Board is found");
+
+        let stones = Grid::from(parsed.board);
+        assert!(stones.iter().any(|(_, stone)| *stone == Stone::Black));
+    }
+
+    #[test]
+    fn test_parse_nine_by_nine_board() {
+        // A drawn board with lines 40 pixels apart, star points and a few
+        // black stones.
+        let mut image =
+            image::RgbImage::from_pixel(640, 640, image::Rgb([220, 180, 120]));
+        let intersection = |i: u32| 60 + 40 * i;
+        let mut fill = |x: u32, y: u32, radius: i32| {
+            for dy in -radius..=radius {
+                for dx in -radius..=radius {
+                    if dx * dx + dy * dy <= radius * radius {
+                        let (x, y) = (x as i32 + dx, y as i32 + dy);
+                        image.put_pixel(
+                            x as u32,
+                            y as u32,
+                            image::Rgb([20; 3]),
+                        );
+                    }
+                }
+            }
+        };
+        for i in 0..9 {
+            for j in intersection(0)..=intersection(8) {
+                fill(intersection(i), j, 1);
+                fill(j, intersection(i), 1);
+            }
+        }
+        for (x, y) in &[(2, 2), (2, 6), (4, 4), (6, 2), (6, 6)] {
+            fill(intersection(*x), intersection(*y), 4);
+        }
+        let stones = [(1, 1), (3, 1), (5, 2), (1, 5), (3, 7), (7, 7), (8, 4)];
+        for (x, y) in &stones {
+            fill(intersection(*x), intersection(*y), 17);
+        }
+
+        let parsed = parse_image(image, &ParserConfig::default())
+            .expect("Board is found");
+        assert_eq!(9, parsed.size());
+        assert!(parsed.size_confidence > 0.9);
+        let board = Grid::from(parsed.board);
+        for (x, y) in &stones {
+            assert_eq!(Stone::Black, board[(*y as usize, *x as usize)]);
+        }
+    }
+
+    #[test]
+    fn test_too_few_black_stones() {
+        let image = image::RgbImage::from_pixel(200, 200, image::Rgb([200; 3]));
+        let error = parse_image(image, &ParserConfig::default()).unwrap_err();
+
+        assert_eq!(
+            ParseError::TooFewBlackStones {
+                found: 0,
+                required: 6
+            },
+            error
+        );
+        assert_eq!(
+            "Found 0 black stones, but at least 6 are required",
+            error.to_string()
+        );
+
+        let config = ParserConfig::builder().min_black_stones(10).build();
+        let image = image::RgbImage::from_pixel(200, 200, image::Rgb([200; 3]));
+        assert_eq!(
+            ParseError::TooFewBlackStones {
+                found: 0,
+                required: 10
+            },
+            parse_image(image, &config).unwrap_err()
+        );
     }
 }
@@ -0,0 +1,640 @@
+//! Finds the lines of the board grid in the image, so that the board can be
+//! located even if there are only a few stones on it.
+//!
+//! A pixel is on a line if it's darker than the pixels on both of its sides.
+//! Which direction is across the line tells the second derivative of the
+//! luminance, in which the line is a valley. Each line pixel then votes in a
+//! Hough transform for the lines which pass through it, but only for those
+//! which are about as steep as the valley. Edges of the stones or of the board
+//! have a bright side and a dark side, hence they don't vote at all.
+//!
+//! The lines with the most votes are split into two families by their angle,
+//! and in each family the longest chain of regularly spaced lines is the grid.
+//!
+//! Once the board is located, its lattice can be refined without the Hough
+//! transform by looking for each line right across where the lattice puts it.
+
+use crate::board::XYTuple;
+use crate::color;
+use crate::homography::Homography;
+use crate::num_ext::NumExt;
+
+// How far from a pixel on a line are its sides, in pixels. The lines must be
+// thinner than twice this.
+const LINE_HALF_WIDTH: usize = 3;
+// How much darker than both of its sides must a pixel be to be on a line.
+const MIN_LINE_CONTRAST: f32 = 6.0;
+// The resolution of the line angle in the Hough transform, in radians.
+const ANGLE_STEP: f32 = std::f32::consts::PI / 360.0;
+// How many angle steps to either side of the gradient direction each edge
+// pixel votes for. The direction of the gradient is only approximate.
+const ANGLE_VOTE_SPREAD: isize = 6;
+// Lines with less votes than this portion of the most voted line are ignored.
+const MIN_VOTES_TO_MAX: f32 = 0.1;
+// Of lines closer than this many pixels and angle steps, only the most voted
+// one is kept. Each grid line has two edges, one on either side.
+const PEAK_DISTANCE: isize = 8;
+const PEAK_ANGLE: isize = 6;
+// Lines in one family differ in angle by at most this much, in radians,
+// because of the perspective.
+const MAX_FAMILY_ANGLE: f32 = 0.45;
+// The two families of lines are at least this far apart, in radians.
+const MIN_ANGLE_BETWEEN_FAMILIES: f32 = 0.6;
+// The spacing between two neighbouring lines can differ from the previous
+// spacing by at most this portion of it.
+const SPACING_TOLERANCE: f32 = 0.15;
+// The lines can't be closer than this many pixels.
+const MIN_SPACING: f32 = 10.0;
+// A family must have at least this many lines to be a grid.
+const MIN_LINES_IN_FAMILY: usize = 3;
+// How far to either side of where the lattice puts a line is it looked for,
+// and how far from it are its sides, relative to the intersection spacing.
+const REFINE_REACH: f32 = 0.2;
+const REFINE_SIDE: f32 = 0.08;
+// How many times is the lattice fitted to the lines, and to how many points
+// on them at least, relative to the number of lines.
+const REFINE_ITERATIONS: usize = 3;
+const MIN_REFINE_POINTS_PER_LINE: usize = 2;
+
+/// A line in the image, of points `(x, y)` for which
+/// `x * cos(angle) + y * sin(angle) = distance`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Line {
+    /// Angle of the normal of the line, between 0 and pi.
+    pub angle: f32,
+    /// Signed distance of the line from the origin.
+    pub distance: f32,
+    /// How many edge pixels lie on the line.
+    pub votes: u32,
+}
+
+impl Line {
+    fn normal(&self) -> XYTuple {
+        let (sin, cos) = self.angle.sin_cos();
+        XYTuple { x: cos, y: sin }
+    }
+
+    /// The point where the two lines cross, if they aren't parallel.
+    pub(crate) fn intersection(&self, other: &Line) -> Option<XYTuple> {
+        let (a, b) = (self.normal(), other.normal());
+        let determinant = a.x * b.y - a.y * b.x;
+        if determinant.abs() < f32::EPSILON {
+            return None;
+        }
+
+        Some(XYTuple {
+            x: (self.distance * b.y - other.distance * a.y) / determinant,
+            y: (a.x * other.distance - b.x * self.distance) / determinant,
+        })
+    }
+}
+
+/// The grid lines which were found. The columns are ordered from left to right
+/// and the rows from top to bottom. A missing line in the middle is `None`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct GridLines {
+    pub columns: Vec<Option<Line>>,
+    pub rows: Vec<Option<Line>>,
+}
+
+impl GridLines {
+    /// The points where the lines cross, along with the column and row of
+    /// each.
+    pub(crate) fn intersections(&self) -> Vec<((isize, isize), XYTuple)> {
+        let mut intersections = Vec::new();
+        for (column, column_line) in self.columns.iter().enumerate() {
+            for (row, row_line) in self.rows.iter().enumerate() {
+                if let (Some(column_line), Some(row_line)) =
+                    (column_line, row_line)
+                {
+                    if let Some(at) = column_line.intersection(row_line) {
+                        intersections
+                            .push(((column as isize, row as isize), at));
+                    }
+                }
+            }
+        }
+
+        intersections
+    }
+
+    /// The points where the outermost lines cross, that is the corners of the
+    /// board, in the order top left, top right, bottom right and bottom left.
+    /// The first and the last line in each direction are never missing.
+    pub(crate) fn corners(&self) -> Option<[XYTuple; 4]> {
+        let left = self.columns.first()?.as_ref()?;
+        let right = self.columns.last()?.as_ref()?;
+        let top = self.rows.first()?.as_ref()?;
+        let bottom = self.rows.last()?.as_ref()?;
+
+        Some([
+            left.intersection(top)?,
+            right.intersection(top)?,
+            right.intersection(bottom)?,
+            left.intersection(bottom)?,
+        ])
+    }
+}
+
+/// Finds the grid lines of the board in the image. Returns `None` if there
+/// aren't at least a few regularly spaced lines in each direction.
+pub(crate) fn find_grid_lines(image: &image::RgbImage) -> Option<GridLines> {
+    let lines = find_lines(image);
+
+    // The most voted line belongs to one of the families, the most voted line
+    // which is far enough from it in angle to the other one.
+    let first = lines.first()?;
+    let second = lines.iter().find(|line| {
+        angle_difference(line.angle, first.angle) >= MIN_ANGLE_BETWEEN_FAMILIES
+    })?;
+    let family = |of: &Line| -> Vec<Line> {
+        lines
+            .iter()
+            .filter(|line| {
+                angle_difference(line.angle, of.angle) <= MAX_FAMILY_ANGLE
+            })
+            .copied()
+            .collect()
+    };
+    let (first, second) = (family(first), family(second));
+
+    // Columns are the family which is closer to vertical, that is whose
+    // normal is closer to horizontal.
+    let (width, height) = image.dimensions();
+    let center = XYTuple {
+        x: width as f32 / 2.0,
+        y: height as f32 / 2.0,
+    };
+    let verticality = |family: &[Line]| mean_normal(family).x.abs();
+    let (columns, rows) = if verticality(&first) >= verticality(&second) {
+        (first, second)
+    } else {
+        (second, first)
+    };
+    let columns = regular_chain(&columns, center, |n| n.x >= 0.0)?;
+    let rows = regular_chain(&rows, center, |n| n.y >= 0.0)?;
+
+    Some(GridLines { columns, rows })
+}
+
+/// A lattice which was fitted to the grid lines of the board.
+pub(crate) struct LineFit {
+    /// Maps the lattice onto the image.
+    pub homography: Homography,
+    /// The points of the lattice midway between two intersections which the
+    /// homography was fitted to, each along with where its line was found in
+    /// the image.
+    pub points: Vec<(XYTuple, XYTuple)>,
+}
+
+/// Fits the lattice of a board of given size, which the homography maps onto
+/// the image, more precisely onto its grid lines. Each line is looked for
+/// midway between each two intersections, where it isn't crossed by another
+/// line, as the darkest valley across it. The homography is then fitted to
+/// the points which were found. As they're only approximately where the
+/// lattice puts them along the line, the search is repeated a few times.
+/// Returns `None` if too few points were found.
+pub(crate) fn refine_lattice(
+    image: &image::RgbImage,
+    homography: &Homography,
+    size: usize,
+) -> Option<LineFit> {
+    let mut homography = homography.clone();
+    let mut points = Vec::new();
+    for _ in 0..REFINE_ITERATIONS {
+        let mut pairs = Vec::new();
+        for line in 0..size {
+            for between in 1..size {
+                let (line, along) = (line as f32, between as f32 - 0.5);
+                let column = XYTuple { x: line, y: along };
+                let row = XYTuple { x: along, y: line };
+                let across_column = XYTuple { x: 1.0, y: 0.0 };
+                let across_row = XYTuple { x: 0.0, y: 1.0 };
+                for (at, across) in
+                    &[(column, across_column), (row, across_row)]
+                {
+                    if let Some(found) =
+                        find_line_across(image, &homography, *at, *across)
+                    {
+                        pairs.push((*at, found));
+                    }
+                }
+            }
+        }
+
+        if pairs.len() < 2 * size * MIN_REFINE_POINTS_PER_LINE {
+            return None;
+        }
+        homography = Homography::fit(&pairs)?;
+        points = pairs;
+    }
+
+    Some(LineFit { homography, points })
+}
+
+// Where in the image is the line which passes through given lattice point,
+// looking in the direction of the lattice vector `across`.
+fn find_line_across(
+    image: &image::RgbImage,
+    homography: &Homography,
+    at: XYTuple,
+    across: XYTuple,
+) -> Option<XYTuple> {
+    let center = homography.apply(at);
+    let next = homography.apply(XYTuple {
+        x: at.x + across.x,
+        y: at.y + across.y,
+    });
+    let (dx, dy) = (next.x - center.x, next.y - center.y);
+    let spacing = dx.hypot(dy);
+    if !spacing.is_finite() || spacing < MIN_SPACING {
+        return None;
+    }
+
+    let (width, height) = image.dimensions();
+    let point = |offset: f32| XYTuple {
+        x: center.x + dx / spacing * offset,
+        y: center.y + dy / spacing * offset,
+    };
+    let luminance = |offset: f32| {
+        let at = point(offset);
+        let (x, y) = (at.x.round(), at.y.round());
+        if x < 0.0 || y < 0.0 || x >= width as f32 || y >= height as f32 {
+            return None;
+        }
+        Some(color::luminance(image.get_pixel(x as u32, y as u32).0))
+    };
+
+    let side = spacing * REFINE_SIDE;
+    let reach = (spacing * REFINE_REACH).ceil() as i32;
+    let mut best: Option<(f32, f32)> = None;
+    for offset in (-reach..=reach).map(|offset| offset as f32) {
+        let sides = luminance(offset - side)?.min(luminance(offset + side)?);
+        let contrast = sides - luminance(offset)?;
+        if contrast >= MIN_LINE_CONTRAST
+            && best.is_none_or(|(_, best)| contrast > best)
+        {
+            best = Some((offset, contrast));
+        }
+    }
+
+    best.map(|(offset, _)| point(offset))
+}
+
+/// All lines with enough votes, the most voted first.
+fn find_lines(image: &image::RgbImage) -> Vec<Line> {
+    let (width, height) = image.dimensions();
+    let luminance: Vec<f32> =
+        image.pixels().map(|p| color::luminance(p.0)).collect();
+    let (width, height) = (width as usize, height as usize);
+    let at = |x: usize, y: usize| luminance[y * width + x];
+
+    let angles = (std::f32::consts::PI / ANGLE_STEP).round() as usize;
+    let max_distance = ((width * width + height * height) as f32).sqrt();
+    let distances = 2 * max_distance.ceil() as usize + 1;
+    let mut votes = vec![0u32; angles * distances];
+    let trigonometry: Vec<_> = (0..angles)
+        .map(|angle| (angle as f32 * ANGLE_STEP).sin_cos())
+        .collect();
+
+    let d = LINE_HALF_WIDTH;
+    for y in d..height.saturating_sub(d) {
+        for x in d..width.saturating_sub(d) {
+            let center = at(x, y);
+            // The second derivatives tell the direction in which the
+            // luminance curves the most, which is across a line.
+            let xx = at(x + d, y) + at(x - d, y) - 2.0 * center;
+            let yy = at(x, y + d) + at(x, y - d) - 2.0 * center;
+            let xy = (at(x + d, y + d) + at(x - d, y - d)
+                - at(x + d, y - d)
+                - at(x - d, y + d))
+                / 4.0;
+            if xx + yy <= 0.0 {
+                continue;
+            }
+            let direction = 0.5 * f32::atan2(2.0 * xy, xx - yy);
+
+            // A line is darker than both of its sides, unlike an edge.
+            let (sin, cos) = direction.sin_cos();
+            let side = |sign: f32| {
+                let sx = (x as f32 + sign * cos * d as f32).round() as usize;
+                let sy = (y as f32 + sign * sin * d as f32).round() as usize;
+                at(sx, sy)
+            };
+            if side(1.0).min(side(-1.0)) - center < MIN_LINE_CONTRAST {
+                continue;
+            }
+
+            let direction = direction.rem_euclid(std::f32::consts::PI);
+            let center = (direction / ANGLE_STEP).round() as isize;
+            for offset in -ANGLE_VOTE_SPREAD..=ANGLE_VOTE_SPREAD {
+                let angle =
+                    (center + offset).rem_euclid(angles as isize) as usize;
+                let (sin, cos) = trigonometry[angle];
+                let distance = x as f32 * cos + y as f32 * sin;
+                let bin = (distance + max_distance).round() as usize;
+                votes[angle * distances + bin] += 1;
+            }
+        }
+    }
+
+    let max_votes = votes.iter().copied().max().unwrap_or(0);
+    let min_votes = (max_votes as f32 * MIN_VOTES_TO_MAX).max(1.0) as u32;
+    let mut lines = Vec::new();
+    for angle in 0..angles {
+        for bin in 0..distances {
+            let count = votes[angle * distances + bin];
+            if count < min_votes {
+                continue;
+            }
+
+            // Only local maxima are lines. The angle wraps around, and the
+            // line with the angle just below pi is the same as the one with
+            // the angle just above zero and opposite distance.
+            let is_peak = (-PEAK_ANGLE..=PEAK_ANGLE).all(|da| {
+                let other_angle = angle as isize + da;
+                let wraps = other_angle < 0 || other_angle >= angles as isize;
+                let other_angle =
+                    other_angle.rem_euclid(angles as isize) as usize;
+                (-PEAK_DISTANCE..=PEAK_DISTANCE).all(|dd| {
+                    let mut other_bin = bin as isize + dd;
+                    if wraps {
+                        other_bin = distances as isize - 1 - other_bin;
+                    }
+                    if other_bin < 0 || other_bin >= distances as isize {
+                        return true;
+                    }
+                    let other =
+                        votes[other_angle * distances + other_bin as usize];
+                    // Ties are broken by the position, so that only one of
+                    // equal neighbours is a peak.
+                    other < count
+                        || (other == count
+                            && (other_angle, other_bin as usize)
+                                >= (angle, bin))
+                })
+            });
+            if is_peak {
+                lines.push(Line {
+                    angle: angle as f32 * ANGLE_STEP,
+                    distance: bin as f32 - max_distance,
+                    votes: count,
+                });
+            }
+        }
+    }
+
+    lines.sort_by_key(|line| std::cmp::Reverse(line.votes));
+    lines
+}
+
+// Difference between two line angles, between 0 and pi / 2.
+fn angle_difference(a: f32, b: f32) -> f32 {
+    let difference = (a - b).rem_euclid(std::f32::consts::PI);
+    difference.min(std::f32::consts::PI - difference)
+}
+
+// Average normal of the lines. The normals of lines which are close to
+// vertical point either left or right, hence the angles are doubled before
+// averaging.
+fn mean_normal(lines: &[Line]) -> XYTuple {
+    let (sin, cos) = lines.iter().fold((0.0, 0.0), |(sin, cos), line| {
+        let (s, c) = (2.0 * line.angle).sin_cos();
+        (sin + s, cos + c)
+    });
+    let (sin, cos) = (f32::atan2(sin, cos) / 2.0).sin_cos();
+    XYTuple { x: cos, y: sin }
+}
+
+// Finds the longest chain of lines with regular spacing. The lines are
+// measured by where they cross the line through the center along their
+// average normal, which is oriented so that `is_forward` is true for it.
+// With perspective, the spacing grows or shrinks steadily, so each spacing
+// is only compared to the previous one. One line in a row may be missing.
+fn regular_chain(
+    lines: &[Line],
+    center: XYTuple,
+    is_forward: impl Fn(XYTuple) -> bool,
+) -> Option<Vec<Option<Line>>> {
+    let mut normal = mean_normal(lines);
+    if !is_forward(normal) {
+        normal = XYTuple {
+            x: -normal.x,
+            y: -normal.y,
+        };
+    }
+
+    let mut positions: Vec<(f32, Line)> = lines
+        .iter()
+        .filter_map(|line| {
+            let line_normal = line.normal();
+            let cos = normal.x * line_normal.x + normal.y * line_normal.y;
+            if cos.abs() < f32::EPSILON {
+                return None;
+            }
+            let offset = center.x * line_normal.x + center.y * line_normal.y;
+            Some(((line.distance - offset) / cos, *line))
+        })
+        .collect();
+    positions.sort_by(|(a, _), (b, _)| a.partial_ord(*b));
+
+    let mut best: Vec<Option<Line>> = Vec::new();
+    let mut best_votes = 0;
+    for first in 0..positions.len() {
+        for second in first + 1..positions.len() {
+            let spacing = positions[second].0 - positions[first].0;
+            if spacing < MIN_SPACING {
+                continue;
+            }
+
+            let forward = extend_chain(&positions, second, spacing);
+            let backward = extend_chain_backward(&positions, first, spacing);
+            let chain: Vec<_> = backward
+                .into_iter()
+                .rev()
+                .chain(vec![Some(positions[first].1)])
+                .chain(forward)
+                .collect();
+            let votes: u32 = chain.iter().flatten().map(|l| l.votes).sum();
+            if (chain.len(), votes) > (best.len(), best_votes) {
+                best = chain;
+                best_votes = votes;
+            }
+        }
+    }
+
+    if best.iter().flatten().count() >= MIN_LINES_IN_FAMILY {
+        Some(best)
+    } else {
+        None
+    }
+}
+
+// Follows the lines forward from given one, which is `spacing` after the
+// previous line in the chain. Returns the lines including the given one.
+fn extend_chain(
+    positions: &[(f32, Line)],
+    from: usize,
+    spacing: f32,
+) -> Vec<Option<Line>> {
+    let mut chain = vec![Some(positions[from].1)];
+    let (mut last, mut spacing) = (positions[from].0, spacing);
+    loop {
+        let nearest = |expected: f32| {
+            positions
+                .iter()
+                .filter(|(at, _)| {
+                    (at - expected).abs() <= spacing * SPACING_TOLERANCE
+                })
+                .min_by(|(a, _), (b, _)| {
+                    (a - expected).abs().partial_ord((b - expected).abs())
+                })
+                .copied()
+        };
+
+        if let Some((at, line)) = nearest(last + spacing) {
+            chain.push(Some(line));
+            spacing = at - last;
+            last = at;
+        } else if let Some((at, line)) = nearest(last + 2.0 * spacing) {
+            chain.push(None);
+            chain.push(Some(line));
+            spacing = (at - last) / 2.0;
+            last = at;
+        } else {
+            break;
+        }
+    }
+
+    chain
+}
+
+// The same as `extend_chain`, but goes backward from given line, which isn't
+// included. The lines are returned from the closest one.
+fn extend_chain_backward(
+    positions: &[(f32, Line)],
+    from: usize,
+    spacing: f32,
+) -> Vec<Option<Line>> {
+    let mirrored: Vec<_> = positions
+        .iter()
+        .rev()
+        .map(|(at, line)| (-at, *line))
+        .collect();
+    let mut chain =
+        extend_chain(&mirrored, positions.len() - 1 - from, spacing);
+    chain.remove(0);
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASSETS_DIR: &str = "assets/test";
+
+    fn vertical(x: f32, votes: u32) -> Line {
+        Line {
+            angle: 0.0,
+            distance: x,
+            votes,
+        }
+    }
+
+    #[test]
+    fn test_find_grid_lines_on_sparse_boards() {
+        // Boards with too few black stones to find the lattice from them,
+        // the last one of which is taken from a low angle.
+        for test in &["test1", "test7", "test8"] {
+            let image = image::open(format!("{}/{}.jpeg", ASSETS_DIR, test))
+                .expect("Cannot open image")
+                .to_rgb();
+            let grid = find_grid_lines(&image).expect("The grid is found");
+
+            assert_eq!(13, grid.columns.len(), "columns in {}", test);
+            assert_eq!(13, grid.rows.len(), "rows in {}", test);
+            assert_eq!(169, grid.intersections().len(), "in {}", test);
+        }
+    }
+
+    #[test]
+    fn test_grid_corners() {
+        // The board is taken from a low angle, so the far corners are closer
+        // to each other.
+        let image = image::open(format!("{}/test8.jpeg", ASSETS_DIR))
+            .expect("Cannot open image")
+            .to_rgb();
+        let corners = find_grid_lines(&image)
+            .and_then(|grid| grid.corners())
+            .expect("The corners are found");
+
+        let expected = [
+            (476.0, 70.0),
+            (1113.0, 92.0),
+            (1267.0, 730.0),
+            (285.0, 713.0),
+        ];
+        for (corner, (x, y)) in corners.iter().zip(&expected) {
+            assert!(
+                (corner.x - x).abs() < 5.0 && (corner.y - y).abs() < 5.0,
+                "expected ({}, {}), got {:?}",
+                x,
+                y,
+                corner
+            );
+        }
+    }
+
+    #[test]
+    fn test_find_no_grid_lines() {
+        let image = image::RgbImage::from_pixel(200, 200, image::Rgb([200; 3]));
+        assert_eq!(None, find_grid_lines(&image));
+    }
+
+    #[test]
+    fn test_regular_chain() {
+        // Lines whose spacing grows, one of them is missing and there are two
+        // lines which don't belong to the grid.
+        let lines = [
+            vertical(5.0, 100),
+            vertical(100.0, 100),
+            vertical(120.0, 100),
+            vertical(141.0, 100),
+            vertical(186.0, 100),
+            vertical(195.0, 100),
+            vertical(210.0, 100),
+        ];
+        let center = XYTuple { x: 150.0, y: 150.0 };
+
+        let chain = regular_chain(&lines, center, |n| n.x >= 0.0)
+            .expect("The chain is found");
+        let distances: Vec<_> =
+            chain.iter().map(|line| line.map(|l| l.distance)).collect();
+        assert_eq!(
+            vec![
+                Some(100.0),
+                Some(120.0),
+                Some(141.0),
+                None,
+                Some(186.0),
+                Some(210.0)
+            ],
+            distances
+        );
+    }
+
+    #[test]
+    fn test_line_intersection() {
+        let horizontal = Line {
+            angle: std::f32::consts::FRAC_PI_2,
+            distance: 20.0,
+            votes: 0,
+        };
+        let at = vertical(10.0, 0)
+            .intersection(&horizontal)
+            .expect("The lines cross");
+
+        assert!((at.x - 10.0).abs() < 1e-4 && (at.y - 20.0).abs() < 1e-4);
+        assert_eq!(None, horizontal.intersection(&horizontal));
+    }
+}
@@ -0,0 +1,442 @@
+//! A compact image of which pixels are set, and the objects which the set
+//! pixels make up.
+//!
+//! A phone photo has over ten million pixels, hence the mask takes a bit per
+//! pixel rather than a byte. The objects are found in two passes over the
+//! horizontal runs of set pixels rather than over the pixels. The first pass
+//! joins each run with the runs in the row above which it touches, and the
+//! second one collects the runs of each object. The rows are joined in bands
+//! which don't depend on each other, and then the bands are joined too.
+
+use crate::parallel;
+use crate::Point;
+use std::ops::Range;
+
+// How many rows are joined together before the bands are joined with each
+// other.
+const BAND_ROWS: usize = 64;
+
+/// One bit per pixel, row by row. Each row starts at a new word, so that the
+/// empty parts of a row are skipped a word at a time.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct BitMask {
+    width: u32,
+    height: u32,
+    words_per_row: usize,
+    words: Vec<u64>,
+}
+
+/// Pixels from `start` to `end`, both inclusive, on row `y` which are all set.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Run {
+    pub y: u32,
+    pub start: u32,
+    pub end: u32,
+}
+
+impl BitMask {
+    /// A mask with none of the pixels set.
+    pub(crate) fn new(width: u32, height: u32) -> Self {
+        let words_per_row = (width as usize).div_ceil(64);
+        Self {
+            width,
+            height,
+            words_per_row,
+            words: vec![0; words_per_row * height as usize],
+        }
+    }
+
+    /// A mask with the pixels set for which the function returns true. The
+    /// rows are filled in parallel, see the `parallel` module.
+    pub(crate) fn from_fn<F>(width: u32, height: u32, f: F) -> Self
+    where
+        F: Fn(u32, u32) -> bool + Sync + Send,
+    {
+        let mut mask = Self::new(width, height);
+        let mut rows: Vec<_> = mask
+            .words
+            .chunks_mut(mask.words_per_row.max(1))
+            .enumerate()
+            .collect();
+        parallel::for_each_mut(&mut rows, |(y, row)| {
+            for x in 0..width {
+                if f(x, *y as u32) {
+                    row[x as usize / 64] |= 1 << (x % 64);
+                }
+            }
+        });
+
+        mask
+    }
+
+    // Only the tests and the debug images read single pixels.
+    #[cfg(test)]
+    pub(crate) fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub(crate) fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Whether the pixel is set. Pixels out of the mask aren't.
+    #[cfg(test)]
+    pub(crate) fn get(&self, x: u32, y: u32) -> bool {
+        if x >= self.width || y >= self.height {
+            return false;
+        }
+
+        let word =
+            self.words[y as usize * self.words_per_row + x as usize / 64];
+        word & (1 << (x % 64)) != 0
+    }
+
+    /// Appends the runs of set pixels on given row, from left to right.
+    fn runs(&self, y: u32, runs: &mut Vec<Run>) {
+        let row = y as usize * self.words_per_row;
+        let mut current: Option<Run> = None;
+        for (index, word) in
+            self.words[row..row + self.words_per_row].iter().enumerate()
+        {
+            let mut bits = *word;
+            let mut offset = 0;
+            while bits != 0 {
+                // Skips the clear bits and then counts the set ones.
+                let zeros = bits.trailing_zeros();
+                bits = bits.checked_shr(zeros).unwrap_or(0);
+                let ones = (!bits).trailing_zeros();
+                bits = bits.checked_shr(ones).unwrap_or(0);
+
+                let start = (index * 64) as u32 + offset + zeros;
+                let end = start + ones - 1;
+                offset += zeros + ones;
+                // A run which ends at the last bit of a word might go on in
+                // the next one.
+                current = match current {
+                    Some(run) if run.end + 1 == start => {
+                        Some(Run { end, ..run })
+                    }
+                    run => {
+                        runs.extend(run);
+                        Some(Run { y, start, end })
+                    }
+                };
+            }
+        }
+
+        runs.extend(current);
+    }
+}
+
+impl Run {
+    /// The pixels of the run, from left to right.
+    pub(crate) fn pixels(self) -> impl Iterator<Item = Point> {
+        (self.start..=self.end).map(move |x| Point::new(x, self.y))
+    }
+}
+
+/// Finds the objects which the set pixels make up. Two pixels are in the same
+/// object if they touch by a side or by a corner. Returns the runs of each
+/// object row by row, in the order in which the objects start in the mask.
+pub(crate) fn objects(mask: &BitMask) -> Vec<Vec<Run>> {
+    let rows: Vec<_> = (0..mask.height()).collect();
+    let rows = parallel::map(&rows, |y| {
+        let mut runs = Vec::new();
+        mask.runs(*y, &mut runs);
+        runs
+    });
+    // The runs of row `y` are from `row_starts[y]` to `row_starts[y + 1]`.
+    let mut row_starts = vec![0];
+    row_starts.extend(rows.iter().scan(0, |end, row| {
+        *end += row.len();
+        Some(*end)
+    }));
+    let runs = rows.concat();
+
+    // Each run points to a run of the same object which comes before it, and
+    // the first run of the object points to itself. Within a band, the runs
+    // point to each other by their index in the band, and only once the band
+    // is joined they point to their index among all the runs.
+    let mut parents = vec![0; runs.len()];
+    let mut bands = Vec::new();
+    let mut rest = parents.as_mut_slice();
+    for first_row in (0..rows.len()).step_by(BAND_ROWS) {
+        let band_rows = first_row..(first_row + BAND_ROWS).min(rows.len());
+        let band_runs = row_starts[band_rows.end] - row_starts[first_row];
+        let (band, tail) = std::mem::take(&mut rest).split_at_mut(band_runs);
+        bands.push((band_rows, band));
+        rest = tail;
+    }
+    parallel::for_each_mut(&mut bands, |(band_rows, parents)| {
+        let offset = row_starts[band_rows.start];
+        let row = |y: usize| row_starts[y] - offset..row_starts[y + 1] - offset;
+        for (index, parent) in parents.iter_mut().enumerate() {
+            *parent = index;
+        }
+        for y in band_rows.start + 1..band_rows.end {
+            join_rows(parents, &runs[offset..], row(y - 1), row(y));
+        }
+        for parent in parents.iter_mut() {
+            *parent += offset;
+        }
+    });
+    drop(bands);
+
+    // The root of each object is its first run whichever runs are joined
+    // first, hence the objects are the same as if the rows were joined one
+    // after another.
+    let row = |y: usize| row_starts[y]..row_starts[y + 1];
+    for y in (BAND_ROWS..rows.len()).step_by(BAND_ROWS) {
+        join_rows(&mut parents, &runs, row(y - 1), row(y));
+    }
+
+    // The first run of each object is its root, hence the objects are ordered
+    // by where they start.
+    let mut objects: Vec<Vec<Run>> = Vec::new();
+    let mut object_of_root = vec![usize::MAX; runs.len()];
+    for (index, run) in runs.iter().enumerate() {
+        let root = find(&mut parents, index);
+        if root == index {
+            object_of_root[root] = objects.len();
+            objects.push(Vec::new());
+        }
+        objects[object_of_root[root]].push(*run);
+    }
+
+    objects
+}
+
+/// Joins the runs of a row with the runs of the row above it which they touch.
+fn join_rows(
+    parents: &mut [usize],
+    runs: &[Run],
+    previous_row: Range<usize>,
+    row: Range<usize>,
+) {
+    // Both rows are sorted, hence the runs which touch are found in a single
+    // sweep.
+    let mut above = previous_row.start;
+    for index in row {
+        let run = runs[index];
+        while above < previous_row.end && runs[above].end + 1 < run.start {
+            above += 1;
+        }
+
+        let mut touching = above;
+        while touching < previous_row.end && runs[touching].start <= run.end + 1
+        {
+            union(parents, touching, index);
+            touching += 1;
+        }
+    }
+}
+
+/// The first run of the object which the run belongs to. The runs on the way
+/// are pointed to the ones two steps further, which shortens the next search.
+fn find(parents: &mut [usize], mut index: usize) -> usize {
+    while parents[index] != index {
+        parents[index] = parents[parents[index]];
+        index = parents[index];
+    }
+
+    index
+}
+
+/// Joins the objects of the two runs, keeping the earlier first run as the
+/// root.
+fn union(parents: &mut [usize], a: usize, b: usize) {
+    let (a, b) = (find(parents, a), find(parents, b));
+    let (root, child) = (a.min(b), a.max(b));
+    parents[child] = root;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mask_from(rows: &[&str]) -> BitMask {
+        BitMask::from_fn(rows[0].len() as u32, rows.len() as u32, |x, y| {
+            rows[y as usize].as_bytes()[x as usize] == b'#'
+        })
+    }
+
+    #[test]
+    fn test_from_fn() {
+        let mask = BitMask::from_fn(70, 3, |x, y| x == y * 30);
+
+        assert!(mask.get(0, 0));
+        assert!(mask.get(30, 1));
+        assert!(mask.get(60, 2));
+        assert!(!mask.get(60, 1));
+    }
+
+    #[test]
+    fn test_get() {
+        let mask = BitMask::from_fn(130, 3, |x, y| (x, y) == (0, 0) || x == 64);
+
+        assert!(mask.get(0, 0));
+        assert!(mask.get(64, 1));
+        assert!(!mask.get(63, 1));
+        assert!(!mask.get(129, 2));
+        assert!(!mask.get(130, 0));
+        assert!(!mask.get(0, 3));
+    }
+
+    #[test]
+    fn test_runs_across_words() {
+        let mask = BitMask::from_fn(200, 1, |x, _| {
+            (10..20).contains(&x) || (60..140).contains(&x) || x == 199
+        });
+
+        let mut runs = Vec::new();
+        mask.runs(0, &mut runs);
+        let ranges: Vec<_> = runs.iter().map(|r| (r.start, r.end)).collect();
+        assert_eq!(vec![(10, 19), (60, 139), (199, 199)], ranges);
+    }
+
+    #[test]
+    fn test_objects() {
+        let mask = mask_from(&[
+            "##...#..#",
+            ".#..#...#",
+            "...#..###",
+            "#.......#",
+            "##.##....",
+        ]);
+
+        let objects = objects(&mask);
+        let pixels: Vec<Vec<_>> = objects
+            .iter()
+            .map(|runs| {
+                runs.iter()
+                    .flat_map(|run| run.pixels())
+                    .map(|p| (p.x, p.y))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                vec![(0, 0), (1, 0), (1, 1)],
+                // The pixels which touch by a corner are in one object, even
+                // if it bends back up.
+                vec![(5, 0), (4, 1), (3, 2)],
+                vec![(8, 0), (8, 1), (6, 2), (7, 2), (8, 2), (8, 3)],
+                vec![(0, 3), (0, 4), (1, 4)],
+                vec![(3, 4), (4, 4)],
+            ],
+            pixels
+        );
+    }
+
+    #[test]
+    fn test_objects_match_flood_fill() {
+        // The objects in the test images are the same as the ones which a
+        // flood fill over a grid of booleans finds, which the parser used
+        // before.
+        for test in 1..=8 {
+            let path = format!("assets/test/test{}.jpeg", test);
+            let image =
+                image::open(&path).expect("Cannot open image").to_luma();
+            let mask =
+                BitMask::from_fn(image.width(), image.height(), |x, y| {
+                    image.get_pixel(x, y).0[0] < 60
+                });
+
+            let mut labeled: Vec<Vec<_>> = objects(&mask)
+                .into_iter()
+                .map(|runs| {
+                    let pixels = runs.into_iter().flat_map(Run::pixels);
+                    let mut pixels: Vec<_> =
+                        pixels.map(|p| (p.x, p.y)).collect();
+                    pixels.sort_unstable();
+                    pixels
+                })
+                .collect();
+            labeled.sort();
+
+            assert_eq!(flood_fill_objects(&mask), labeled, "in {}", path);
+        }
+    }
+
+    /// The objects with their pixels sorted, in the order of their pixels.
+    fn flood_fill_objects(mask: &BitMask) -> Vec<Vec<(u32, u32)>> {
+        let (width, height) = (mask.width() as i64, mask.height() as i64);
+        let mut grid: Vec<Vec<bool>> = (0..mask.height())
+            .map(|y| (0..mask.width()).map(|x| mask.get(x, y)).collect())
+            .collect();
+        let mut is_set = |x: i64, y: i64| {
+            let set = x >= 0
+                && y >= 0
+                && x < width
+                && y < height
+                && grid[y as usize][x as usize];
+            if set {
+                grid[y as usize][x as usize] = false;
+            }
+            set
+        };
+
+        let mut objects = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                if !is_set(x, y) {
+                    continue;
+                }
+
+                let mut pixels = Vec::new();
+                let mut queue = vec![(x, y)];
+                while let Some((x, y)) = queue.pop() {
+                    pixels.push((x as u32, y as u32));
+                    for ny in y - 1..=y + 1 {
+                        for nx in x - 1..=x + 1 {
+                            if is_set(nx, ny) {
+                                queue.push((nx, ny));
+                            }
+                        }
+                    }
+                }
+                pixels.sort_unstable();
+                objects.push(pixels);
+            }
+        }
+
+        objects.sort();
+        objects
+    }
+
+    #[test]
+    fn test_objects_across_bands() {
+        // A "U" whose arms are only joined at the bottom of the third band,
+        // and a line which touches the first one only by a corner at the
+        // border of two bands.
+        let height = BAND_ROWS as u32 * 3;
+        let mask = BitMask::from_fn(10, height, |x, y| {
+            let y = y as usize;
+            ((x == 0 || x == 4) && y < BAND_ROWS * 3)
+                || (x < 5 && y == BAND_ROWS * 3 - 1)
+                || (x == 7 && y < BAND_ROWS)
+                || (x == 6 && (BAND_ROWS..BAND_ROWS + 2).contains(&y))
+        });
+
+        let objects = objects(&mask);
+        assert_eq!(2, objects.len());
+        assert_eq!(
+            Run {
+                y: 0,
+                start: 0,
+                end: 0
+            },
+            objects[0][0]
+        );
+        assert_eq!(BAND_ROWS * 3 * 2 - 1, objects[0].len());
+        assert_eq!(
+            Run {
+                y: 0,
+                start: 7,
+                end: 7
+            },
+            objects[1][0]
+        );
+        assert_eq!(BAND_ROWS + 2, objects[1].len());
+    }
+}
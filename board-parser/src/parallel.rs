@@ -0,0 +1,34 @@
+//! Runs the data parallel stages of the parser on all cores with the
+//! `parallel` feature, and one item after another without it. Either way, the
+//! results are in the order of the items, hence the parsed board doesn't
+//! depend on the feature.
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// Applies the function to each item, returning the results in order.
+pub(crate) fn map<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync + Send,
+{
+    #[cfg(feature = "parallel")]
+    let results = items.par_iter().map(f).collect();
+    #[cfg(not(feature = "parallel"))]
+    let results = items.iter().map(f).collect();
+
+    results
+}
+
+/// Applies the function to each item, which it changes in place.
+pub(crate) fn for_each_mut<T, F>(items: &mut [T], f: F)
+where
+    T: Send,
+    F: Fn(&mut T) + Sync + Send,
+{
+    #[cfg(feature = "parallel")]
+    items.par_iter_mut().for_each(f);
+    #[cfg(not(feature = "parallel"))]
+    items.iter_mut().for_each(f);
+}
@@ -0,0 +1,225 @@
+//! Finds the board in a smaller copy of the image, and then refines where it
+//! is in the image itself.
+//!
+//! Phones take photos several thousand pixels large, while the board can be
+//! found in a photo a fraction of that size, and several times faster. The
+//! image is therefore halved as many times as it takes to get it under the
+//! detection size, each pixel of a smaller level being the mean of four
+//! pixels of the larger one. Once the lattice is found, it's mapped onto the
+//! original image, and the centres of the black stones are found again in the
+//! original pixels. If the lattice was fitted to the stones, it's fitted again
+//! to their new centres. The size of the board is then inferred from the
+//! original image, in which the star points are several pixels large. At
+//! last, the lattice is fitted to the grid lines of the board in the original
+//! image, which are too thin to be found precisely in the smaller one. The
+//! lattice of an image which is searched as it is gets fitted to them too.
+
+use crate::board::{self, Lattice, LocatedBoard, XYTuple};
+use crate::color;
+use crate::homography::Homography;
+use crate::lines;
+use crate::{ParseError, ParserConfig};
+use std::collections::HashMap;
+
+// How far from its centre in the smaller image is a black stone looked for in
+// the original one, relative to the intersection spacing. Under a half of it,
+// the stones on the next intersections stay out of reach.
+const REFINE_RADIUS: f32 = 0.45;
+// The stones in images smaller than this are too small to be told apart from
+// noise, whatever the detection size.
+const MIN_DETECTION_SIZE: u32 = 256;
+
+/// Locates the board in the image, see the module documentation. Images which
+/// are small enough are searched as they are.
+pub(crate) fn locate_board(
+    image: &image::RgbImage,
+    config: &ParserConfig,
+) -> Result<LocatedBoard, ParseError> {
+    let mut level = None;
+    let mut scale = 1;
+    let detection_size = config.detection_size.max(MIN_DETECTION_SIZE);
+    while image.width().max(image.height()) / scale > detection_size {
+        level = Some(halve(level.as_ref().unwrap_or(image)));
+        scale *= 2;
+    }
+    let mut located = match level {
+        Some(level) => {
+            // The size of the stones is given in the pixels of the original
+            // image.
+            let scale = scale as f32;
+            let level_config = ParserConfig {
+                min_stone_size: config.min_stone_size / scale,
+                ..config.clone()
+            };
+            let lattice = board::find_lattice(&level, &level_config)?;
+            board::place_board(image, refine(image, lattice, scale))?
+        }
+        None => board::board_map(image, config)?,
+    };
+
+    // The lattice which was fitted to the stones is only as precise as their
+    // centres, hence it's fitted to the grid lines even if the image wasn't
+    // made smaller.
+    if let Some(fit) =
+        lines::refine_lattice(image, &located.homography, located.size)
+    {
+        located.homography = fit.homography;
+        located.line_points = fit.points;
+    }
+
+    Ok(located)
+}
+
+/// Halves both dimensions of the image, rounding down.
+fn halve(image: &image::RgbImage) -> image::RgbImage {
+    image::RgbImage::from_fn(image.width() / 2, image.height() / 2, |x, y| {
+        let mut sums = [0u32; 3];
+        for (dx, dy) in &[(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let pixel = image.get_pixel(x * 2 + dx, y * 2 + dy);
+            for (sum, channel) in sums.iter_mut().zip(&pixel.0) {
+                *sum += *channel as u32;
+            }
+        }
+
+        image::Rgb([
+            (sums[0] / 4) as u8,
+            (sums[1] / 4) as u8,
+            (sums[2] / 4) as u8,
+        ])
+    })
+}
+
+/// Maps the lattice which was found in an image `scale` times smaller onto
+/// the original image. The black stones are found again around where they
+/// were.
+fn refine(image: &image::RgbImage, lattice: Lattice, scale: f32) -> Lattice {
+    // The centre of a pixel of the smaller image is in the middle of the
+    // pixels of the original one which it was averaged from.
+    let offset = (scale - 1.0) / 2.0;
+    let to_original = Homography::similarity(
+        XYTuple {
+            x: offset,
+            y: offset,
+        },
+        0.0,
+        scale,
+    );
+    let homography = to_original
+        .compose(&lattice.homography)
+        .expect("A similarity keeps the bottom row of the lattice");
+
+    let stones: HashMap<_, _> = lattice
+        .stones
+        .into_iter()
+        .map(|(intersection, stone)| {
+            let at = to_original.apply(stone);
+            let spacing = homography.spacing_at(intersection.into());
+            let refined = stone_center(image, at, spacing * REFINE_RADIUS);
+            (intersection, refined.unwrap_or(at))
+        })
+        .collect();
+
+    // The grid lines are more precise than the stones, which are seldom
+    // right on their intersections, hence only the lattice of the stones is
+    // fitted again.
+    let homography = if lattice.fitted_to_stones {
+        let pairs: Vec<_> = stones
+            .iter()
+            .map(|(intersection, stone)| (XYTuple::from(*intersection), *stone))
+            .collect();
+        Homography::fit(&pairs).unwrap_or(homography)
+    } else {
+        homography
+    };
+
+    let unplaced = lattice
+        .unplaced
+        .iter()
+        .map(|stone| to_original.apply(*stone))
+        .collect();
+    let mut corners = lattice.corners;
+    for corner in corners.iter_mut().flatten() {
+        *corner = to_original.apply(*corner);
+    }
+
+    Lattice {
+        homography,
+        stones,
+        unplaced,
+        corners,
+        ..lattice
+    }
+}
+
+/// The centre of mass of the dark pixels within given radius from the point.
+/// The stone is the darkest thing there, hence a pixel is dark if it's closer
+/// to the darkest pixel than to the brightest one.
+fn stone_center(
+    image: &image::RgbImage,
+    at: XYTuple,
+    radius: f32,
+) -> Option<XYTuple> {
+    let (width, height) = image.dimensions();
+    let left = (at.x - radius).floor().max(0.0) as u32;
+    let top = (at.y - radius).floor().max(0.0) as u32;
+    let right = ((at.x + radius).ceil().max(0.0) as u32).min(width);
+    let bottom = ((at.y + radius).ceil().max(0.0) as u32).min(height);
+
+    let pixels: Vec<_> = (top..bottom)
+        .flat_map(|y| (left..right).map(move |x| (x, y)))
+        .filter(|(x, y)| (*x as f32 - at.x).hypot(*y as f32 - at.y) <= radius)
+        .map(|(x, y)| (x, y, color::luminance(image.get_pixel(x, y).0)))
+        .collect();
+
+    let darkest = pixels.iter().map(|(_, _, l)| *l).fold(f32::MAX, f32::min);
+    let brightest = pixels.iter().map(|(_, _, l)| *l).fold(f32::MIN, f32::max);
+    let threshold = (darkest + brightest) / 2.0;
+
+    let dark: Vec<_> = pixels
+        .iter()
+        .filter(|(_, _, luminance)| *luminance < threshold)
+        .collect();
+    if dark.is_empty() {
+        return None;
+    }
+
+    let count = dark.len() as f32;
+    Some(XYTuple {
+        x: dark.iter().map(|(x, _, _)| *x as f32).sum::<f32>() / count,
+        y: dark.iter().map(|(_, y, _)| *y as f32).sum::<f32>() / count,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_halve() {
+        let image = image::RgbImage::from_fn(5, 4, |x, y| {
+            image::Rgb([(x * 10) as u8, (y * 10) as u8, 100])
+        });
+
+        let half = halve(&image);
+        assert_eq!((2, 2), half.dimensions());
+        assert_eq!([5, 5, 100], half.get_pixel(0, 0).0);
+        assert_eq!([25, 25, 100], half.get_pixel(1, 1).0);
+    }
+
+    #[test]
+    fn test_stone_center() {
+        let image = image::RgbImage::from_fn(100, 100, |x, y| {
+            let distance = (x as f32 - 40.5).hypot(y as f32 - 60.0);
+            if distance <= 12.0 {
+                image::Rgb([10, 10, 10])
+            } else {
+                image::Rgb([200, 170, 120])
+            }
+        });
+
+        let center = stone_center(&image, XYTuple { x: 43.0, y: 58.0 }, 16.0)
+            .expect("The stone is found");
+        assert!((center.x - 40.5).abs() < 0.1, "x is {}", center.x);
+        assert!((center.y - 60.0).abs() < 0.1, "y is {}", center.y);
+    }
+}
@@ -0,0 +1,341 @@
+//! Tells how many lines the board has and where on the lattice it lies.
+//!
+//! The lattice is only known around the stones. Each of the standard sizes is
+//! therefore tried at every position at which the board covers some of the
+//! stones and fits into the image. The candidates are compared by how likely
+//! they make what's seen in the image: the corners of the board where the
+//! outermost grid lines cross, if those were found, the star points (hoshi),
+//! which are dark dots on a few intersections at known distance from the
+//! edges, and the black stones, few of which are off the board.
+
+use crate::board::{Intersection, XYTuple};
+use crate::classify;
+use crate::homography::Homography;
+use crate::Point;
+use std::collections::HashMap;
+
+const SIZES: [usize; 3] = [9, 13, 19];
+// An intersection at least this dark is a star point, see
+// `classify::dark_portion`. A plain intersection is about a half dark.
+const STAR_POINT_DARKNESS: f32 = 0.75;
+// How likely is a star point seen, it's hidden if a white stone is on it, and
+// how likely does a plain intersection look like one.
+const STAR_POINT_SEEN: f32 = 0.9;
+const FALSE_STAR_POINT: f32 = 0.05;
+// How far from the true corners of the board are the grid line corners, in
+// units of the intersection spacing. The outermost line may also be missed,
+// hence the likelihood of a corner never drops below a floor.
+const CORNER_DEVIATION: f32 = 0.3;
+const MIN_CORNER_LIKELIHOOD: f32 = 0.01;
+// How many intersections to either side of the corner of the grid lines is the
+// board tried if there are no stones to limit where it is.
+const CORNER_SEARCH_RADIUS: isize = 2;
+// How likely is a black stone off the board, e.g. in a bowl or by the board.
+const STONE_OFF_BOARD: f32 = 0.001;
+
+/// The board as located on the lattice.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct BoardExtent {
+    /// How many lines does the board have in each direction.
+    pub size: usize,
+    /// The lattice intersection of the top left corner of the board.
+    pub origin: (isize, isize),
+    /// How likely is the size right, between 0 and 1.
+    pub confidence: f32,
+}
+
+/// Finds the size and the position of the board on the lattice. The
+/// homography maps the lattice onto the image, the stones are the black stones
+/// placed on it and the corners are where the outermost grid lines cross.
+/// Returns `None` if no standard board covers any of the stones and fits into
+/// the image.
+pub(crate) fn infer_extent(
+    image: &image::RgbImage,
+    homography: &Homography,
+    stones: &HashMap<Intersection, XYTuple>,
+    corners: Option<&[XYTuple; 4]>,
+) -> Option<BoardExtent> {
+    // The corners are compared with the intersections on the lattice.
+    let corners = match corners {
+        Some(corners) => {
+            let to_lattice = homography.inverse()?;
+            let mut on_lattice = *corners;
+            for corner in &mut on_lattice {
+                *corner = to_lattice.apply(*corner);
+            }
+            Some(on_lattice)
+        }
+        None => None,
+    };
+
+    let mut star_points = StarPoints {
+        image,
+        homography,
+        stones,
+        is_dark: HashMap::new(),
+    };
+    // The most likely position of each size, with its log likelihood.
+    let mut best: Vec<(usize, (isize, isize), f32)> = Vec::new();
+    for &size in &SIZES {
+        let mut best_of_size = None;
+        for origin in origins(size, stones, corners.as_ref()) {
+            if !fits_into_image(image, homography, size, origin) {
+                continue;
+            }
+
+            let mut likelihood = star_points.log_likelihood(size, origin);
+            let off_board = stones
+                .keys()
+                .filter(|stone| !covers(size, origin, **stone))
+                .count();
+            likelihood += off_board as f32 * STONE_OFF_BOARD.ln();
+            if let Some(corners) = &corners {
+                likelihood += corners_log_likelihood(corners, size, origin);
+            }
+            // Of equally likely candidates, the first one is kept.
+            match best_of_size {
+                Some((_, best)) if best >= likelihood => (),
+                _ => best_of_size = Some((origin, likelihood)),
+            }
+        }
+
+        if let Some((origin, likelihood)) = best_of_size {
+            best.push((size, origin, likelihood));
+        }
+    }
+
+    // The sizes are equally likely up front, hence the confidence is the
+    // likelihood of the best one relative to all of them. Of equally likely
+    // sizes, the smallest one is chosen.
+    let max = best
+        .iter()
+        .map(|(_, _, likelihood)| *likelihood)
+        .fold(f32::NEG_INFINITY, f32::max);
+    let total: f32 = best
+        .iter()
+        .map(|(_, _, likelihood)| (likelihood - max).exp())
+        .sum();
+    let (size, origin, _) =
+        *best.iter().find(|(_, _, likelihood)| *likelihood >= max)?;
+
+    Some(BoardExtent {
+        size,
+        origin,
+        confidence: 1.0 / total,
+    })
+}
+
+/// The lattice intersections of the top left corner at which the board covers
+/// some of the stones. If there are no stones, the board is looked for around
+/// the corner of the grid lines.
+fn origins(
+    size: usize,
+    stones: &HashMap<Intersection, XYTuple>,
+    corners: Option<&[XYTuple; 4]>,
+) -> Vec<(isize, isize)> {
+    let range = |coordinates: Vec<isize>, corner: Option<f32>| {
+        let min = coordinates.iter().min();
+        let max = coordinates.iter().max();
+        match (min, max, corner) {
+            (Some(min), Some(max), _) => {
+                (min - size as isize + 1..=*max).collect()
+            }
+            (_, _, Some(corner)) => {
+                let corner = corner.round() as isize;
+                (corner - CORNER_SEARCH_RADIUS..=corner + CORNER_SEARCH_RADIUS)
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    };
+
+    let columns: Vec<_> = range(
+        stones.keys().map(|(column, _)| *column).collect(),
+        corners.map(|corners| corners[0].x),
+    );
+    let rows: Vec<_> = range(
+        stones.keys().map(|(_, row)| *row).collect(),
+        corners.map(|corners| corners[0].y),
+    );
+
+    columns
+        .iter()
+        .flat_map(|column| rows.iter().map(move |row| (*column, *row)))
+        .collect()
+}
+
+/// Whether the board at given origin covers the lattice intersection.
+pub(crate) fn covers(
+    size: usize,
+    (left, top): (isize, isize),
+    (column, row): Intersection,
+) -> bool {
+    let size = size as isize;
+    (left..left + size).contains(&column) && (top..top + size).contains(&row)
+}
+
+/// Whether all four corners of the board are in the image.
+fn fits_into_image(
+    image: &image::RgbImage,
+    homography: &Homography,
+    size: usize,
+    origin: (isize, isize),
+) -> bool {
+    let (width, height) = image.dimensions();
+    board_corners(size, origin).iter().all(|corner| {
+        let at = homography.apply(*corner);
+        (0.0..width as f32).contains(&at.x)
+            && (0.0..height as f32).contains(&at.y)
+    })
+}
+
+/// The lattice intersections of the corners of the board, in the order top
+/// left, top right, bottom right and bottom left.
+fn board_corners(size: usize, (column, row): (isize, isize)) -> [XYTuple; 4] {
+    let (left, top) = (column as f32, row as f32);
+    let (right, bottom) = (left + size as f32 - 1.0, top + size as f32 - 1.0);
+    [
+        XYTuple { x: left, y: top },
+        XYTuple { x: right, y: top },
+        XYTuple {
+            x: right,
+            y: bottom,
+        },
+        XYTuple { x: left, y: bottom },
+    ]
+}
+
+/// How likely are the grid line corners if the board is at given position,
+/// relative to them being right on the board corners.
+fn corners_log_likelihood(
+    corners: &[XYTuple; 4],
+    size: usize,
+    origin: (isize, isize),
+) -> f32 {
+    corners
+        .iter()
+        .zip(&board_corners(size, origin))
+        .map(|(found, expected)| {
+            let distance =
+                (found.x - expected.x).powi(2) + (found.y - expected.y).powi(2);
+            (-distance / (2.0 * CORNER_DEVIATION.powi(2)))
+                .max(MIN_CORNER_LIKELIHOOD.ln())
+        })
+        .sum()
+}
+
+/// The star points on a board of given size, as `(column, row)` from the top
+/// left corner. Besides the middle one, they're on the third line from the
+/// edge on small boards and on the fourth one otherwise.
+fn star_points(size: usize) -> Vec<(isize, isize)> {
+    let size = size as isize;
+    let edge = if size < 13 { 2 } else { 3 };
+    let middle = size / 2;
+    let lines = if size < 19 {
+        vec![edge, size - edge - 1]
+    } else {
+        vec![edge, middle, size - edge - 1]
+    };
+
+    let mut points: Vec<_> = lines
+        .iter()
+        .flat_map(|column| lines.iter().map(move |row| (*column, *row)))
+        .collect();
+    if !points.contains(&(middle, middle)) {
+        points.push((middle, middle));
+    }
+
+    points
+}
+
+/// Looks for star points on the lattice intersections, remembering what it
+/// has seen.
+struct StarPoints<'a> {
+    image: &'a image::RgbImage,
+    homography: &'a Homography,
+    stones: &'a HashMap<Intersection, XYTuple>,
+    is_dark: HashMap<(isize, isize), Option<bool>>,
+}
+
+impl<'a> StarPoints<'a> {
+    /// How likely is what's seen on the star points of a board of given size
+    /// at given position, relative to there being no star points at all.
+    /// Star points covered by black stones or outside of the image don't tell
+    /// anything.
+    fn log_likelihood(&mut self, size: usize, origin: (isize, isize)) -> f32 {
+        let seen = (STAR_POINT_SEEN / FALSE_STAR_POINT).ln();
+        let unseen = ((1.0 - STAR_POINT_SEEN) / (1.0 - FALSE_STAR_POINT)).ln();
+
+        star_points(size)
+            .into_iter()
+            .map(|(column, row)| (origin.0 + column, origin.1 + row))
+            .map(|at| match self.is_dark(at) {
+                Some(true) => seen,
+                Some(false) => unseen,
+                None => 0.0,
+            })
+            .sum()
+    }
+
+    fn is_dark(&mut self, (column, row): (isize, isize)) -> Option<bool> {
+        if self.stones.contains_key(&(column, row)) {
+            return None;
+        }
+
+        let (image, homography) = (self.image, self.homography);
+        *self.is_dark.entry((column, row)).or_insert_with(|| {
+            let intersection = XYTuple::from((column, row));
+            let at = homography.apply(intersection);
+            let (width, height) = image.dimensions();
+            if !(0.0..width as f32).contains(&at.x)
+                || !(0.0..height as f32).contains(&at.y)
+            {
+                return None;
+            }
+
+            let spacing = homography.spacing_at(intersection);
+            let point = Point::new(at.x.round() as u32, at.y.round() as u32);
+            classify::dark_portion(image, point, spacing)
+                .map(|dark| dark >= STAR_POINT_DARKNESS)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_star_points() {
+        let mut nine = star_points(9);
+        nine.sort();
+        assert_eq!(vec![(2, 2), (2, 6), (4, 4), (6, 2), (6, 6)], nine);
+
+        let mut thirteen = star_points(13);
+        thirteen.sort();
+        assert_eq!(vec![(3, 3), (3, 9), (6, 6), (9, 3), (9, 9)], thirteen);
+
+        let nineteen = star_points(19);
+        assert_eq!(9, nineteen.len());
+        assert!(nineteen.contains(&(9, 9)));
+        assert!(nineteen.contains(&(15, 3)));
+    }
+
+    #[test]
+    fn test_corners_log_likelihood() {
+        // The grid line corners of a 13x13 board at (-6, -6).
+        let corners = board_corners(13, (-6, -6));
+
+        let right = corners_log_likelihood(&corners, 13, (-6, -6));
+        let smaller = corners_log_likelihood(&corners, 9, (-6, -6));
+        assert_eq!(0.0, right);
+        assert!(smaller < right);
+
+        // A corner which is one line off is as unlikely as one which is far
+        // off, because the outermost line may have been missed.
+        let shifted = corners_log_likelihood(&corners, 13, (-5, -6));
+        let far = corners_log_likelihood(&corners, 13, (-1, -6));
+        assert!(shifted < smaller);
+        assert_eq!(shifted, far);
+    }
+}
@@ -0,0 +1,170 @@
+//! Tells which pixels of the image belong to black stones, whatever the
+//! lighting.
+//!
+//! A fixed threshold either misses the stones in a bright photo, where their
+//! reflections are grey, or takes the whole board for black in a dim one.
+//! Under a warm light, the stones aren't even grey but brown. The colours are
+//! therefore first balanced so that the image is grey on average, which is
+//! known as the grey world assumption. A pixel is then black if it's grey and
+//! much darker than the mean of the pixels around it.
+
+use crate::board::BlackPixels;
+use crate::color::luminance;
+use crate::mask::BitMask;
+use crate::ParserConfig;
+
+// The side of the window over which the local mean is taken, relative to the
+// larger image dimension. The window must be several stones large so that
+// there's some board in it even within a group of black stones.
+const WINDOW_TO_IMAGE_SIZE: f32 = 0.125;
+
+/// Marks the pixels which belong to black stones. No pixel brighter than the
+/// black threshold is black, however bright its surroundings are, otherwise
+/// the reflections on the stones would join neighbouring stones. The rows are
+/// thresholded in parallel, see the `parallel` module.
+pub(crate) fn black_pixels(
+    image: &image::RgbImage,
+    config: &ParserConfig,
+) -> BlackPixels {
+    let (width, height) = image.dimensions();
+    let (width, height) = (width as usize, height as usize);
+    let gains = white_balance(image);
+
+    let balanced: Vec<[f32; 3]> = image
+        .pixels()
+        .map(|pixel| {
+            let [r, g, b] = pixel.0;
+            [
+                r as f32 * gains[0],
+                g as f32 * gains[1],
+                b as f32 * gains[2],
+            ]
+        })
+        .collect();
+    let luminance: Vec<_> = balanced.iter().map(|p| luminance(*p)).collect();
+
+    let radius = (width.max(height) as f32 * WINDOW_TO_IMAGE_SIZE / 2.0)
+        .round()
+        .max(1.0) as usize;
+    let local_mean = LocalMean::new(&luminance, width, height);
+
+    BitMask::from_fn(width as u32, height as u32, |x, y| {
+        let (x, y) = (x as usize, y as usize);
+        let index = y * width + x;
+        let [r, g, b] = balanced[index];
+        let limit = config.grayness_limit;
+        let is_gray = (r - g).abs() <= limit
+            && (r - b).abs() <= limit
+            && (g - b).abs() <= limit;
+        let threshold = (local_mean.around(x, y, radius)
+            * config.black_to_local_mean)
+            .min(config.black_threshold);
+
+        is_gray && luminance[index] < threshold
+    })
+}
+
+/// Gains of the red, green and blue channel which make the mean colour of
+/// the image grey, keeping its luminance.
+fn white_balance(image: &image::RgbImage) -> [f32; 3] {
+    let mut sums = [0u64; 3];
+    for pixel in image.pixels() {
+        for (sum, channel) in sums.iter_mut().zip(&pixel.0) {
+            *sum += *channel as u64;
+        }
+    }
+
+    let count = (image.width() as u64 * image.height() as u64).max(1);
+    let means = [
+        sums[0] as f32 / count as f32,
+        sums[1] as f32 / count as f32,
+        sums[2] as f32 / count as f32,
+    ];
+    let gray = luminance(means);
+
+    let mut gains = [1.0; 3];
+    for (gain, mean) in gains.iter_mut().zip(&means) {
+        if *mean > 0.0 {
+            *gain = gray / mean;
+        }
+    }
+
+    gains
+}
+
+/// Sums of all values above and to the left of each pixel, from which the
+/// mean over any rectangle is computed in constant time.
+struct LocalMean {
+    sums: Vec<f64>,
+    width: usize,
+    height: usize,
+}
+
+impl LocalMean {
+    fn new(values: &[f32], width: usize, height: usize) -> Self {
+        // There's an extra row and column of zeros at the top and left.
+        let mut sums = vec![0.0; (width + 1) * (height + 1)];
+        for y in 0..height {
+            let mut row_sum = 0.0;
+            for x in 0..width {
+                row_sum += values[y * width + x] as f64;
+                sums[(y + 1) * (width + 1) + x + 1] =
+                    sums[y * (width + 1) + x + 1] + row_sum;
+            }
+        }
+
+        Self {
+            sums,
+            width,
+            height,
+        }
+    }
+
+    /// The mean of the values in the square of given radius around the pixel,
+    /// cut off by the edges of the image.
+    fn around(&self, x: usize, y: usize, radius: usize) -> f32 {
+        let (left, top) = (x.saturating_sub(radius), y.saturating_sub(radius));
+        let right = (x + radius + 1).min(self.width);
+        let bottom = (y + radius + 1).min(self.height);
+        let at = |x: usize, y: usize| self.sums[y * (self.width + 1) + x];
+
+        let sum = at(right, bottom) - at(left, bottom) - at(right, top)
+            + at(left, top);
+        let count = (right - left) * (bottom - top);
+        (sum / count as f64) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_mean() {
+        let values: Vec<_> = (0..12).map(|value| value as f32).collect();
+        let mean = LocalMean::new(&values, 4, 3);
+
+        assert_eq!(5.0, mean.around(1, 1, 1));
+        assert_eq!(2.5, mean.around(0, 0, 1));
+        assert_eq!(5.5, mean.around(2, 1, 5));
+    }
+
+    #[test]
+    fn test_black_pixels_under_warm_light() {
+        // A dim orange board with a stone which is dark brown, and a patch
+        // which is only a bit darker than the board.
+        let mut image =
+            image::RgbImage::from_pixel(400, 400, image::Rgb([120, 80, 40]));
+        for y in 40..50 {
+            for x in 40..50 {
+                image.put_pixel(x, y, image::Rgb([12, 8, 4]));
+                image.put_pixel(x - 30, y, image::Rgb([80, 50, 30]));
+            }
+        }
+
+        let black = black_pixels(&image, &ParserConfig::default());
+        assert!(black.get(45, 45));
+        assert!(!black.get(15, 45));
+        assert!(!black.get(10, 10));
+    }
+}
@@ -0,0 +1,330 @@
+//! Stones which are left on the board at the end of the game even though both
+//! players know they would be captured. They have to be taken off the board
+//! before the territory is assigned, otherwise the territory they sit in
+//! belongs to nobody.
+
+use crate::group::{find_grid_groups, Group};
+use crate::{count_score, empty_region, neutral_reason};
+use crate::{Board, Grid, Neutral, Score, Stone, Territory};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+
+// If the empty intersections enclosed by a group are at least this many, we
+// assume the group can always make two eyes in there.
+const LARGE_EYE_SPACE: usize = 7;
+
+/// How many stones did each player capture.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Captures {
+    /// White stones taken off the board.
+    pub by_black: usize,
+    /// Black stones taken off the board.
+    pub by_white: usize,
+}
+
+/// Takes the stones at given `(row, column)` positions off the board and
+/// counts them as captured by the opponent. Positions which are empty or out
+/// of the board are ignored.
+pub fn remove_dead_stones(
+    board: &mut Board<Stone>,
+    dead: &HashSet<(usize, usize)>,
+) -> Captures {
+    let mut stones = Grid::from(board.clone());
+    let captures = remove_dead_grid_stones(&mut stones, dead);
+    *board = Board::try_from(stones)
+        .expect("The stones have the same size as the board");
+
+    captures
+}
+
+/// Same as [`remove_dead_stones`], but for boards of any size.
+pub fn remove_dead_grid_stones(
+    stones: &mut Grid<Stone>,
+    dead: &HashSet<(usize, usize)>,
+) -> Captures {
+    let mut captures = Captures::default();
+    for at in dead {
+        let stone = match stones.get_mut(*at) {
+            Some(stone) => stone,
+            None => continue,
+        };
+
+        match stone {
+            Stone::Black => captures.by_white += 1,
+            Stone::White => captures.by_black += 1,
+            Stone::None => (),
+        }
+        *stone = Stone::None;
+    }
+
+    captures
+}
+
+/// Removes the stones which the user marked as dead and assigns the territory
+/// again as if they were never on the board.
+pub fn count_score_without_dead(
+    mut board: Board<Stone>,
+    dead: &HashSet<(usize, usize)>,
+) -> (Board<Territory>, Score, Captures) {
+    let captures = remove_dead_stones(&mut board, dead);
+    let (territory, score) = count_score(board);
+    (territory, score, captures)
+}
+
+/// Suggests groups which are likely dead, so that the user only has to
+/// confirm them. Each suggestion is a list of `(row, column)` positions of
+/// stones in one group.
+///
+/// A group which has two eyes, or enough eye space to make them, is alive.
+/// For every empty region which both colours border we compare the groups
+/// around it. If only one side has a living group there, the other side's
+/// groups are dead. If neither side does, the side with fewer stones and
+/// liberties is dead. A group is only suggested when it's dead in every
+/// region it borders, and never when it's in seki. Once some groups are
+/// removed, others may gain eyes, so this repeats until no new dead groups
+/// are found.
+pub fn suggest_dead_stones(board: &Board<Stone>) -> Vec<Vec<(usize, usize)>> {
+    let mut stones = Grid::from(board.clone());
+    let mut suggestions = Vec::new();
+
+    loop {
+        let dead = find_dead_groups(&stones);
+        if dead.is_empty() {
+            break;
+        }
+
+        for group in &dead {
+            for point in &group.stones {
+                stones[*point] = Stone::None;
+            }
+        }
+        suggestions.extend(dead.into_iter().map(|group| group.stones));
+    }
+
+    suggestions.sort();
+    suggestions
+}
+
+/// Empty intersections which are connected by lines on the board.
+struct Region {
+    owner: Territory,
+    points: Vec<(usize, usize)>,
+}
+
+/// What we know about the groups of one colour around a shared region.
+#[derive(Default)]
+struct Side {
+    has_living_group: bool,
+    strength: usize,
+    groups: Vec<usize>,
+}
+
+fn find_dead_groups(stones: &Grid<Stone>) -> Vec<Group> {
+    let groups = find_grid_groups(stones);
+
+    // Finds all empty regions and remembers which region each empty
+    // intersection belongs to.
+    let mut regions: Vec<Region> = Vec::new();
+    let mut region_at: HashMap<(usize, usize), usize> = HashMap::new();
+    let mut visited = Grid::new(stones.size(), false);
+    for (point, stone) in stones.iter() {
+        if *stone != Stone::None || visited[point] {
+            continue;
+        }
+        let (points, owner) = empty_region(stones, &mut visited, point);
+        for point in &points {
+            region_at.insert(*point, regions.len());
+        }
+        regions.push(Region { owner, points });
+    }
+
+    // For each group lists the regions it borders.
+    let group_regions: Vec<HashSet<usize>> = groups
+        .iter()
+        .map(|group| group.liberties.iter().map(|l| region_at[l]).collect())
+        .collect();
+
+    // Decides for each group whether it's alive on its own, without
+    // looking at the surrounding groups.
+    let alive: Vec<bool> = groups
+        .iter()
+        .zip(&group_regions)
+        .map(|(group, bordered_regions)| {
+            let own_regions = bordered_regions.iter().filter(|region| {
+                let owner = regions[**region].owner;
+                (owner == Territory::Black && group.color == Stone::Black)
+                    || (owner == Territory::White
+                        && group.color == Stone::White)
+            });
+            let eyes = own_regions.clone().count();
+            let eye_space: usize = own_regions
+                .map(|region| regions[*region].points.len())
+                .sum();
+
+            eyes >= 2 || eye_space >= LARGE_EYE_SPACE
+        })
+        .collect();
+
+    // How many shared regions consider given group dead, and how many shared
+    // regions does the group border in total.
+    let mut dead_votes = vec![0; groups.len()];
+    let mut shared_regions = vec![0; groups.len()];
+    for (region_index, region) in regions.iter().enumerate() {
+        if region.owner != Territory::None {
+            continue;
+        }
+
+        let mut black = Side::default();
+        let mut white = Side::default();
+        for (index, group) in groups.iter().enumerate() {
+            if !group_regions[index].contains(&region_index) {
+                continue;
+            }
+
+            let side = if group.color == Stone::Black {
+                &mut black
+            } else {
+                &mut white
+            };
+            side.has_living_group |= alive[index];
+            side.strength += group.stones.len() + group.liberties.len();
+            side.groups.push(index);
+            shared_regions[index] += 1;
+        }
+
+        // Groups which share liberties in seki are alive even though they
+        // don't have two eyes.
+        let is_seki = region
+            .points
+            .iter()
+            .any(|point| neutral_reason(stones, *point) == Some(Neutral::Seki));
+        if black.groups.is_empty() || white.groups.is_empty() || is_seki {
+            continue;
+        }
+
+        let dead_side = match (black.has_living_group, white.has_living_group) {
+            (true, false) => Some(&white),
+            (false, true) => Some(&black),
+            (false, false) if black.strength > white.strength => Some(&white),
+            (false, false) if white.strength > black.strength => Some(&black),
+            _ => None,
+        };
+        for index in dead_side.map(|side| &side.groups[..]).unwrap_or(&[]) {
+            dead_votes[*index] += 1;
+        }
+    }
+
+    groups
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| {
+            dead_votes[*index] > 0
+                && dead_votes[*index] == shared_regions[*index]
+                && !alive[*index]
+        })
+        .map(|(_, group)| group)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{nine_by_nine, seki_board};
+
+    #[test]
+    fn test_count_score_without_dead() {
+        let board = nine_by_nine([
+            "...xo....",
+            "...xo....",
+            "...xo.xx.",
+            "...xo.x..",
+            "...xo....",
+            ".o.xo....",
+            "...xo....",
+            "...xo....",
+            "...xo....",
+        ]);
+        let dead = vec![(5, 1), (2, 6), (2, 7), (3, 6)].into_iter().collect();
+        let (_, score, captures) = count_score_without_dead(board, &dead);
+
+        assert_eq!(
+            Captures {
+                by_black: 1,
+                by_white: 3
+            },
+            captures
+        );
+        assert_eq!(27, score.black_territory);
+        assert_eq!(36, score.white_territory);
+    }
+
+    #[test]
+    fn test_remove_dead_stones_ignores_empty_and_out_of_board() {
+        let mut board = nine_by_nine([
+            "x........",
+            ".........",
+            ".........",
+            ".........",
+            ".........",
+            ".........",
+            ".........",
+            ".........",
+            "........o",
+        ]);
+        let dead = vec![(0, 0), (4, 4), (9, 9), (100, 0)].into_iter().collect();
+        let captures = remove_dead_stones(&mut board, &dead);
+
+        assert_eq!(
+            Captures {
+                by_black: 0,
+                by_white: 1
+            },
+            captures
+        );
+        let (_, score) = count_score(board);
+        assert_eq!(0, score.black_stones);
+        assert_eq!(1, score.white_stones);
+    }
+
+    #[test]
+    fn test_suggest_dead_stones() {
+        let board = nine_by_nine([
+            "...xo....",
+            "...xo....",
+            "...xo.xx.",
+            "...xo.x..",
+            "...xo....",
+            ".o.xo....",
+            "...xo....",
+            "...xo....",
+            "...xo....",
+        ]);
+
+        assert_eq!(
+            vec![vec![(2, 6), (2, 7), (3, 6)], vec![(5, 1)]],
+            suggest_dead_stones(&board)
+        );
+    }
+
+    #[test]
+    fn test_living_groups_are_not_suggested() {
+        let board = nine_by_nine([
+            "..x.o....",
+            "..x.o....",
+            "..x.o....",
+            ".xx.oo...",
+            "xx...o...",
+            ".....oooo",
+            "xxxxxo...",
+            "....xo...",
+            "....xo...",
+        ]);
+
+        assert!(suggest_dead_stones(&board).is_empty());
+    }
+
+    #[test]
+    fn test_groups_in_seki_are_not_suggested() {
+        assert!(suggest_dead_stones(&seki_board()).is_empty());
+    }
+}
@@ -0,0 +1,360 @@
+//! Applies moves to a position according to the rules of go. A stone which
+//! takes the last liberty of opponent's groups captures them. A move which
+//! would leave its own group without liberties is suicide and is rejected. So
+//! is a move which immediately retakes a ko, or optionally a move which repeats
+//! any earlier position.
+
+use crate::dead::Captures;
+use crate::group::{find_grid_groups, group_at};
+use crate::{Board, Grid, Stone};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+
+/// Which repetitions of a position are forbidden.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KoRule {
+    /// A single stone which just captured a single stone cannot be captured
+    /// back right away.
+    Simple,
+    /// On top of the simple ko, no move can recreate a position which was
+    /// already on the board earlier in the game.
+    PositionalSuperko,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MoveError {
+    /// Only black and white stones can be played.
+    NoStone,
+    OutOfBoard,
+    Occupied,
+    Suicide,
+    Ko,
+    Superko,
+}
+
+impl fmt::Display for MoveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            MoveError::NoStone => "only a black or a white stone can be played",
+            MoveError::OutOfBoard => "the intersection is out of the board",
+            MoveError::Occupied => "there already is a stone",
+            MoveError::Suicide => "the stone would have no liberties",
+            MoveError::Ko => "the ko cannot be retaken right away",
+            MoveError::Superko => "the move repeats an earlier position",
+        };
+        write!(f, "Illegal move: {}", reason)
+    }
+}
+
+impl std::error::Error for MoveError {}
+
+#[derive(Clone, Debug)]
+pub struct Position {
+    stones: Grid<Stone>,
+    ko_rule: KoRule,
+    // The intersection which cannot be played by given colour in the next
+    // move because of a simple ko.
+    ko: Option<((usize, usize), Stone)>,
+    // Zobrist hash of the stones on the board.
+    hash: u64,
+    // Hashes of all positions which were on the board so far.
+    history: HashSet<u64>,
+    captures: Captures,
+}
+
+impl Position {
+    /// Starts with an empty board with given number of lines.
+    pub fn new(size: usize, ko_rule: KoRule) -> Self {
+        Self::from_grid(Grid::new(size, Stone::None), ko_rule)
+    }
+
+    /// Starts with the stones which are already on the board, e.g. a handicap.
+    pub fn from_board(board: Board<Stone>, ko_rule: KoRule) -> Self {
+        Self::from_grid(Grid::from(board), ko_rule)
+    }
+
+    /// Same as [`Position::from_board`], but for boards of any size.
+    pub fn from_grid(stones: Grid<Stone>, ko_rule: KoRule) -> Self {
+        let hash = stones
+            .iter()
+            .fold(0, |hash, (at, stone)| hash ^ zobrist(at, *stone));
+        let mut history = HashSet::new();
+        history.insert(hash);
+
+        Self {
+            stones,
+            ko_rule,
+            ko: None,
+            hash,
+            history,
+            captures: Captures::default(),
+        }
+    }
+
+    pub fn stones(&self) -> &Grid<Stone> {
+        &self.stones
+    }
+
+    /// Returns `None` if the board isn't 9x9, 13x13 or 19x19.
+    pub fn board(&self) -> Option<Board<Stone>> {
+        Board::try_from(self.stones.clone()).ok()
+    }
+
+    /// How many stones did each player capture by playing moves.
+    pub fn captures(&self) -> Captures {
+        self.captures
+    }
+
+    /// Hash of the stones on the board. Equal positions have equal hashes,
+    /// regardless of the moves which led to them.
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+
+    /// Places a stone of given colour at given `(row, column)` and removes
+    /// the opponent's groups which it captured. Returns positions of the
+    /// captured stones. If the move is illegal, the position is unchanged.
+    pub fn play(
+        &mut self,
+        color: Stone,
+        at: (usize, usize),
+    ) -> Result<Vec<(usize, usize)>, MoveError> {
+        let opponent = match color {
+            Stone::Black => Stone::White,
+            Stone::White => Stone::Black,
+            Stone::None => return Err(MoveError::NoStone),
+        };
+        match self.stones.get(at) {
+            None => return Err(MoveError::OutOfBoard),
+            Some(Stone::None) => (),
+            Some(_) => return Err(MoveError::Occupied),
+        }
+        if self.ko == Some((at, color)) {
+            return Err(MoveError::Ko);
+        }
+
+        let mut stones = self.stones.clone();
+        let mut hash = self.hash ^ zobrist(at, color);
+        stones[at] = color;
+
+        // Opponent's groups next to the new stone which have no liberties
+        // left are taken off the board.
+        let mut captured = Vec::new();
+        for neighbor in self.stones.neighbors(at) {
+            if stones[neighbor] != opponent {
+                continue;
+            }
+            let group = group_at(&stones, neighbor).expect("There's a stone");
+            if group.liberties.is_empty() {
+                for stone in group.stones {
+                    stones[stone] = Stone::None;
+                    hash ^= zobrist(stone, opponent);
+                    captured.push(stone);
+                }
+            }
+        }
+
+        let own_group = group_at(&stones, at).expect("The stone was placed");
+        if own_group.liberties.is_empty() {
+            return Err(MoveError::Suicide);
+        }
+        if self.ko_rule == KoRule::PositionalSuperko
+            && self.history.contains(&hash)
+        {
+            return Err(MoveError::Superko);
+        }
+
+        // A single stone which captured a single stone and is now in atari
+        // could be captured back, repeating the position.
+        self.ko = if captured.len() == 1
+            && own_group.stones.len() == 1
+            && own_group.is_in_atari()
+        {
+            Some((captured[0], opponent))
+        } else {
+            None
+        };
+        match color {
+            Stone::Black => self.captures.by_black += captured.len(),
+            _ => self.captures.by_white += captured.len(),
+        }
+        self.stones = stones;
+        self.hash = hash;
+        self.history.insert(hash);
+
+        captured.sort();
+        Ok(captured)
+    }
+
+    /// Passing doesn't change the board, but it lifts the simple ko.
+    pub fn pass(&mut self) {
+        self.ko = None;
+    }
+
+    /// Puts a stone on the board, or takes it off when given
+    /// [`Stone::None`], without playing a move. Nothing gets captured. This is
+    /// how handicap stones are placed or how an editor changes the position.
+    pub fn setup(
+        &mut self,
+        at: (usize, usize),
+        stone: Stone,
+    ) -> Result<(), MoveError> {
+        let previous =
+            self.stones.set(at, stone).ok_or(MoveError::OutOfBoard)?;
+        self.hash ^= zobrist(at, previous) ^ zobrist(at, stone);
+        self.history.insert(self.hash);
+        self.ko = None;
+
+        Ok(())
+    }
+}
+
+/// A position is legal if every group on the board has at least one liberty.
+/// Positions read from a photo which don't pass this check were recognized
+/// wrongly.
+pub fn is_legal_position(stones: &Grid<Stone>) -> bool {
+    find_grid_groups(stones)
+        .iter()
+        .all(|group| !group.liberties.is_empty())
+}
+
+/// Random number for each intersection and colour. The hash of a position is
+/// the xor of these numbers for all stones on the board. Instead of storing a
+/// table of random numbers, they're derived from the position and colour with
+/// the SplitMix64 generator.
+fn zobrist((row, column): (usize, usize), stone: Stone) -> u64 {
+    let color = match stone {
+        Stone::Black => 1,
+        Stone::White => 2,
+        Stone::None => return 0,
+    };
+    let mut z = ((row as u64) << 34 | (column as u64) << 2 | color)
+        .wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::nine_by_nine;
+
+    // White stone at (1, 1) can be captured by black at (1, 2), which starts
+    // a ko. Black cannot play in the bottom right corner.
+    fn ko_position(ko_rule: KoRule) -> Position {
+        let board = nine_by_nine([
+            ".xo......",
+            "xo.o.....",
+            ".xo......",
+            ".........",
+            ".........",
+            ".........",
+            ".........",
+            "........o",
+            ".......o.",
+        ]);
+        Position::from_board(board, ko_rule)
+    }
+
+    #[test]
+    fn test_capture() {
+        let mut position = ko_position(KoRule::Simple);
+
+        assert_eq!(Ok(vec![(1, 1)]), position.play(Stone::Black, (1, 2)));
+        assert_eq!(Stone::None, position.stones()[(1, 1)]);
+        assert_eq!(
+            Captures {
+                by_black: 1,
+                by_white: 0
+            },
+            position.captures()
+        );
+    }
+
+    #[test]
+    fn test_simple_ko() {
+        let mut position = ko_position(KoRule::Simple);
+        let start = position.hash();
+        position.play(Stone::Black, (1, 2)).unwrap();
+
+        assert_eq!(Err(MoveError::Ko), position.play(Stone::White, (1, 1)));
+
+        // After a ko threat elsewhere the ko can be retaken.
+        position.play(Stone::White, (5, 5)).unwrap();
+        position.play(Stone::Black, (5, 6)).unwrap();
+        assert_eq!(Ok(vec![(1, 2)]), position.play(Stone::White, (1, 1)));
+        assert_ne!(start, position.hash());
+    }
+
+    #[test]
+    fn test_positional_superko() {
+        let mut simple = ko_position(KoRule::Simple);
+        let mut superko = ko_position(KoRule::PositionalSuperko);
+        let start = superko.hash();
+
+        for position in [&mut simple, &mut superko].iter_mut() {
+            position.play(Stone::Black, (1, 2)).unwrap();
+            position.pass();
+            position.pass();
+        }
+
+        // Retaking the ko recreates the starting position.
+        assert_eq!(Ok(vec![(1, 2)]), simple.play(Stone::White, (1, 1)));
+        assert_eq!(start, simple.hash());
+        assert_eq!(Err(MoveError::Superko), superko.play(Stone::White, (1, 1)));
+    }
+
+    #[test]
+    fn test_illegal_moves() {
+        let mut position = ko_position(KoRule::Simple);
+        let start = position.hash();
+
+        assert_eq!(
+            Err(MoveError::Suicide),
+            position.play(Stone::Black, (8, 8))
+        );
+        assert_eq!(
+            Err(MoveError::Occupied),
+            position.play(Stone::White, (0, 1))
+        );
+        assert_eq!(
+            Err(MoveError::OutOfBoard),
+            position.play(Stone::White, (9, 0))
+        );
+        assert_eq!(Err(MoveError::NoStone), position.play(Stone::None, (5, 5)));
+        assert_eq!(start, position.hash());
+    }
+
+    #[test]
+    fn test_capture_is_not_suicide() {
+        let mut position = ko_position(KoRule::Simple);
+
+        assert_eq!(Ok(vec![(0, 1)]), position.play(Stone::White, (0, 0)));
+        assert_eq!(
+            Captures {
+                by_black: 0,
+                by_white: 1
+            },
+            position.captures()
+        );
+    }
+
+    #[test]
+    fn test_is_legal_position() {
+        let board = nine_by_nine([
+            "xo.......",
+            "o........",
+            ".........",
+            ".........",
+            ".........",
+            ".........",
+            ".........",
+            ".........",
+            ".........",
+        ]);
+        assert!(!is_legal_position(&Grid::from(board)));
+        assert!(is_legal_position(ko_position(KoRule::Simple).stones()));
+    }
+}
@@ -0,0 +1,207 @@
+//! A board of any size. Unlike [`Board`], which only has the three most common
+//! sizes, this one can also represent boards such as 7x7 or 11x11 and it can be
+//! walked without matching the size first. All positions are given as
+//! `(row, column)` from the top left corner.
+
+use crate::Board;
+use std::convert::TryFrom;
+use std::ops::{Index, IndexMut};
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Grid<T> {
+    size: usize,
+    // The intersections row by row.
+    cells: Vec<T>,
+}
+
+impl<T: Clone> Grid<T> {
+    /// Creates a board with given number of lines in each direction where
+    /// each intersection is set to given value.
+    pub fn new(size: usize, value: T) -> Self {
+        Self {
+            size,
+            cells: vec![value; size * size],
+        }
+    }
+}
+
+impl<T> Grid<T> {
+    /// How many lines does the board have in each direction.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether given position is on the board.
+    pub fn contains(&self, (row, column): (usize, usize)) -> bool {
+        row < self.size && column < self.size
+    }
+
+    pub fn get(&self, at: (usize, usize)) -> Option<&T> {
+        if self.contains(at) {
+            Some(&self.cells[self.index_of(at)])
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self, at: (usize, usize)) -> Option<&mut T> {
+        if self.contains(at) {
+            let index = self.index_of(at);
+            Some(&mut self.cells[index])
+        } else {
+            None
+        }
+    }
+
+    /// Replaces the value at given position. Returns the value which was there
+    /// before, or `None` if the position is out of the board.
+    pub fn set(&mut self, at: (usize, usize), value: T) -> Option<T> {
+        self.get_mut(at).map(|cell| std::mem::replace(cell, value))
+    }
+
+    /// Up to four positions which are directly connected to given one by a
+    /// line on the board.
+    pub fn neighbors(
+        &self,
+        (row, column): (usize, usize),
+    ) -> impl Iterator<Item = (usize, usize)> {
+        let size = self.size;
+        let up = row.checked_sub(1).map(|row| (row, column));
+        let down = Some(row + 1).filter(|r| *r < size).map(|row| (row, column));
+        let left = column.checked_sub(1).map(|column| (row, column));
+        let right = Some(column + 1)
+            .filter(|c| *c < size)
+            .map(|column| (row, column));
+
+        up.into_iter().chain(down).chain(left).chain(right)
+    }
+
+    /// Visits all intersections row by row, starting in the top left corner.
+    pub fn iter(&self) -> impl Iterator<Item = ((usize, usize), &T)> {
+        let size = self.size;
+        self.cells
+            .iter()
+            .enumerate()
+            .map(move |(index, value)| ((index / size, index % size), value))
+    }
+
+    /// Creates a board of the same size with each value transformed by given
+    /// function.
+    pub fn map<U>(&self, f: impl FnMut(&T) -> U) -> Grid<U> {
+        Grid {
+            size: self.size,
+            cells: self.cells.iter().map(f).collect(),
+        }
+    }
+
+    fn index_of(&self, (row, column): (usize, usize)) -> usize {
+        row * self.size + column
+    }
+}
+
+impl<T> Index<(usize, usize)> for Grid<T> {
+    type Output = T;
+
+    fn index(&self, at: (usize, usize)) -> &T {
+        self.get(at).expect("Position is out of the board")
+    }
+}
+
+impl<T> IndexMut<(usize, usize)> for Grid<T> {
+    fn index_mut(&mut self, at: (usize, usize)) -> &mut T {
+        self.get_mut(at).expect("Position is out of the board")
+    }
+}
+
+impl<T> From<Board<T>> for Grid<T> {
+    fn from(board: Board<T>) -> Self {
+        let size = board.size();
+        let cells = match board {
+            Board::NineByNine(b) => IntoIterator::into_iter(b)
+                .flat_map(IntoIterator::into_iter)
+                .collect(),
+            Board::ThirteenByThirteen(b) => IntoIterator::into_iter(b)
+                .flat_map(IntoIterator::into_iter)
+                .collect(),
+            Board::NineteenByNineteen(b) => IntoIterator::into_iter(b)
+                .flat_map(IntoIterator::into_iter)
+                .collect(),
+        };
+
+        Self { size, cells }
+    }
+}
+
+impl<T> TryFrom<Grid<T>> for Board<T> {
+    /// Boards which aren't 9x9, 13x13 or 19x19 are given back.
+    type Error = Grid<T>;
+
+    fn try_from(grid: Grid<T>) -> Result<Self, Self::Error> {
+        fn rows<T, const N: usize>(cells: Vec<T>) -> [[T; N]; N] {
+            let mut cells = cells.into_iter();
+            std::array::from_fn(|_| {
+                std::array::from_fn(|_| {
+                    cells.next().expect("There must be N * N cells")
+                })
+            })
+        }
+
+        match grid.size {
+            9 => Ok(Board::NineByNine(rows(grid.cells))),
+            13 => Ok(Board::ThirteenByThirteen(rows(grid.cells))),
+            19 => Ok(Board::NineteenByNineteen(rows(grid.cells))),
+            _ => Err(grid),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Stone, Territory};
+
+    #[test]
+    fn test_converts_to_and_from_board() {
+        let mut board = [[Stone::None; 13]; 13];
+        board[2][5] = Stone::Black;
+        board[12][0] = Stone::White;
+        let board = Board::ThirteenByThirteen(board);
+
+        let grid = Grid::from(board.clone());
+        assert_eq!(13, grid.size());
+        assert_eq!(Some(&Stone::Black), grid.get((2, 5)));
+        assert_eq!(Some(&Stone::White), grid.get((12, 0)));
+        assert_eq!(Some(&Stone::None), grid.get((5, 2)));
+        assert_eq!(None, grid.get((13, 0)));
+
+        assert_eq!(Ok(board), Board::try_from(grid));
+    }
+
+    #[test]
+    fn test_odd_sizes_are_not_boards() {
+        let grid = Grid::new(11, Territory::None);
+        assert_eq!(Err(grid.clone()), Board::try_from(grid));
+    }
+
+    #[test]
+    fn test_accessors() {
+        let mut grid = Grid::new(7, Stone::None);
+
+        assert_eq!(Some(Stone::None), grid.set((6, 6), Stone::Black));
+        assert_eq!(Some(Stone::Black), grid.set((6, 6), Stone::White));
+        assert_eq!(None, grid.set((7, 0), Stone::White));
+        assert_eq!(Stone::White, grid[(6, 6)]);
+
+        let mut corner: Vec<_> = grid.neighbors((6, 6)).collect();
+        corner.sort();
+        assert_eq!(vec![(5, 6), (6, 5)], corner);
+        assert_eq!(4, grid.neighbors((3, 3)).count());
+
+        let stones: Vec<_> = grid
+            .iter()
+            .filter(|(_, stone)| **stone != Stone::None)
+            .map(|(at, _)| at)
+            .collect();
+        assert_eq!(vec![(6, 6)], stones);
+    }
+}
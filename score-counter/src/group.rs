@@ -0,0 +1,137 @@
+//! Stones of one colour which are connected by lines on the board live and die
+//! together. The empty intersections next to them are their liberties. Once a
+//! group has no liberties left, it's captured.
+
+use crate::{Board, Grid, Stone};
+use std::collections::HashSet;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Group {
+    /// Either black or white, never none.
+    pub color: Stone,
+    /// Positions as `(row, column)` sorted row by row.
+    pub stones: Vec<(usize, usize)>,
+    /// Empty intersections next to any of the stones.
+    pub liberties: HashSet<(usize, usize)>,
+}
+
+impl Group {
+    /// The group is in atari if the opponent can capture it with one move.
+    pub fn is_in_atari(&self) -> bool {
+        self.liberties.len() == 1
+    }
+
+    pub fn contains(&self, at: (usize, usize)) -> bool {
+        self.stones.binary_search(&at).is_ok()
+    }
+}
+
+/// Finds all groups of stones on the board along with their liberties. The
+/// groups are ordered by their first stone, row by row.
+pub fn find_groups(board: &Board<Stone>) -> Vec<Group> {
+    find_grid_groups(&Grid::from(board.clone()))
+}
+
+/// Same as [`find_groups`], but for boards of any size.
+pub fn find_grid_groups(stones: &Grid<Stone>) -> Vec<Group> {
+    let mut visited = Grid::new(stones.size(), false);
+    let mut groups = Vec::new();
+
+    for (point, stone) in stones.iter() {
+        if *stone == Stone::None || visited[point] {
+            continue;
+        }
+
+        let group = group_at(stones, point).expect("There's a stone here");
+        for stone in &group.stones {
+            visited[*stone] = true;
+        }
+        groups.push(group);
+    }
+
+    groups
+}
+
+/// Finds the group which the stone at given position belongs to. Returns
+/// `None` if the intersection is empty or out of the board.
+pub fn group_at(stones: &Grid<Stone>, start: (usize, usize)) -> Option<Group> {
+    let color = *stones.get(start)?;
+    if color == Stone::None {
+        return None;
+    }
+
+    let mut group = Group {
+        color,
+        stones: Vec::new(),
+        liberties: HashSet::new(),
+    };
+    let mut visited = HashSet::new();
+    visited.insert(start);
+    let mut queue = vec![start];
+    while let Some(point) = queue.pop() {
+        group.stones.push(point);
+        for neighbor in stones.neighbors(point) {
+            if stones[neighbor] == Stone::None {
+                group.liberties.insert(neighbor);
+            } else if stones[neighbor] == color && visited.insert(neighbor) {
+                queue.push(neighbor);
+            }
+        }
+    }
+    group.stones.sort();
+
+    Some(group)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::nine_by_nine;
+
+    #[test]
+    fn test_find_groups() {
+        let board = nine_by_nine([
+            "xo.......",
+            "xo.......",
+            ".........",
+            ".........",
+            "....x....",
+            "...xox...",
+            ".........",
+            ".........",
+            "........x",
+        ]);
+        let groups = find_groups(&board);
+
+        assert_eq!(7, groups.len());
+
+        assert_eq!(Stone::Black, groups[0].color);
+        assert_eq!(vec![(0, 0), (1, 0)], groups[0].stones);
+        assert!(groups[0].is_in_atari());
+
+        assert_eq!(Stone::White, groups[1].color);
+        assert_eq!(vec![(0, 1), (1, 1)], groups[1].stones);
+        assert_eq!(3, groups[1].liberties.len());
+        assert!(!groups[1].is_in_atari());
+
+        let surrounded = groups
+            .iter()
+            .find(|group| group.contains((5, 4)))
+            .expect("There's a stone at (5, 4)");
+        assert_eq!(Stone::White, surrounded.color);
+        assert!(surrounded.is_in_atari());
+        assert!(surrounded.liberties.contains(&(6, 4)));
+
+        let corner = groups.last().unwrap();
+        assert_eq!(vec![(8, 8)], corner.stones);
+        assert_eq!(2, corner.liberties.len());
+    }
+
+    #[test]
+    fn test_group_at_empty_intersection() {
+        let stones = Grid::new(9, Stone::None);
+
+        assert_eq!(None, group_at(&stones, (4, 4)));
+        assert_eq!(None, group_at(&stones, (9, 9)));
+    }
+}
@@ -1,29 +1,609 @@
+pub mod dead;
+pub mod game;
+pub mod grid;
+pub mod group;
+pub mod sgf;
+
+use dead::Captures;
+pub use grid::Grid;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum Stone {
     White,
     Black,
     None,
 }
 
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum Territory {
     White,
     Black,
     None,
 }
 
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Board<T> {
     NineByNine([[T; 9]; 9]),
     ThirteenByThirteen([[T; 13]; 13]),
     NineteenByNineteen([[T; 19]; 19]),
 }
 
-pub fn count_score(_board: Board<Stone>) -> Board<Territory> {
-    unimplemented!()
+impl<T> Board<T> {
+    /// How many lines does the board have in each direction.
+    pub fn size(&self) -> usize {
+        match self {
+            Board::NineByNine(_) => 9,
+            Board::ThirteenByThirteen(_) => 13,
+            Board::NineteenByNineteen(_) => 19,
+        }
+    }
+}
+
+/// Why an empty intersection belongs to nobody.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Neutral {
+    /// Neither player gains anything by playing there.
+    Dame,
+    /// A liberty shared by groups of both colours which neither player can
+    /// fill without putting their own group into atari, or an eye of such a
+    /// group. Eyes in seki aren't territory, but they count towards the area.
+    Seki,
+}
+
+/// Summary of the area score. Each player gets a point for each of their
+/// stones on the board and for each empty intersection they surround.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Score {
+    pub black_stones: usize,
+    pub white_stones: usize,
+    pub black_territory: usize,
+    pub white_territory: usize,
+    /// Empty intersections surrounded by black groups in seki.
+    pub black_seki_eyes: usize,
+    /// Empty intersections surrounded by white groups in seki.
+    pub white_seki_eyes: usize,
+    /// Empty intersections as `(row, column)` which are nobody's territory,
+    /// along with the reason why.
+    pub neutral: HashMap<(usize, usize), Neutral>,
+}
+
+impl Score {
+    /// Stones, territory and eyes in seki of the black player.
+    pub fn black_area(&self) -> usize {
+        self.black_stones + self.black_territory + self.black_seki_eyes
+    }
+
+    /// Stones, territory and eyes in seki of the white player.
+    pub fn white_area(&self) -> usize {
+        self.white_stones + self.white_territory + self.white_seki_eyes
+    }
+}
+
+/// Flood fills empty regions of the board. A region which borders stones of
+/// one colour only is that colour's territory. Regions which border both
+/// colours, or none at all, belong to nobody. Intersections with a stone on
+/// them are never territory. Shared liberties of groups in seki are reported
+/// in [`Score::neutral`], so that they can be told apart from dame. So are the
+/// eyes of groups in seki, which aren't territory either.
+pub fn count_score(board: Board<Stone>) -> (Board<Territory>, Score) {
+    let (territory, score) = count_grid_score(&Grid::from(board));
+    let territory = Board::try_from(territory)
+        .expect("Territory has the same size as the board");
+
+    (territory, score)
+}
+
+/// Same as [`count_score`], but for boards of any size.
+pub fn count_grid_score(stones: &Grid<Stone>) -> (Grid<Territory>, Score) {
+    let mut territory = Grid::new(stones.size(), Territory::None);
+    let mut score = Score::default();
+    let mut visited = Grid::new(stones.size(), false);
+
+    let mut owned_regions = Vec::new();
+    for (point, stone) in stones.iter() {
+        match stone {
+            Stone::Black => score.black_stones += 1,
+            Stone::White => score.white_stones += 1,
+            Stone::None if !visited[point] => {
+                match empty_region(stones, &mut visited, point) {
+                    (region, Territory::None) => {
+                        for point in region {
+                            if let Some(reason) = neutral_reason(stones, point)
+                            {
+                                score.neutral.insert(point, reason);
+                            }
+                        }
+                    }
+                    region => owned_regions.push(region),
+                }
+            }
+            Stone::None => (),
+        }
+    }
+
+    // The groups which share a liberty in seki. Their eyes are only known
+    // once all the shared liberties are.
+    let mut in_seki = HashSet::new();
+    for (point, reason) in &score.neutral {
+        if *reason == Neutral::Seki {
+            for neighbor in stones.neighbors(*point) {
+                if let Some(group) = group::group_at(stones, neighbor) {
+                    in_seki.extend(group.stones);
+                }
+            }
+        }
+    }
+
+    for (region, owner) in owned_regions {
+        let is_seki_eye = region.iter().any(|point| {
+            stones
+                .neighbors(*point)
+                .any(|neighbor| in_seki.contains(&neighbor))
+        });
+        if is_seki_eye {
+            match owner {
+                Territory::Black => score.black_seki_eyes += region.len(),
+                _ => score.white_seki_eyes += region.len(),
+            }
+            for point in region {
+                score.neutral.insert(point, Neutral::Seki);
+            }
+            continue;
+        }
+
+        for point in &region {
+            territory[*point] = owner;
+        }
+        match owner {
+            Territory::Black => score.black_territory += region.len(),
+            _ => score.white_territory += region.len(),
+        }
+    }
+
+    (territory, score)
+}
+
+/// Which rules are used to turn a board into the game result.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Rules {
+    /// Also known as Chinese rules. A player's score is the number of their
+    /// stones on the board plus the territory they surround.
+    Area,
+    /// Also known as Japanese rules. A player's score is the territory they
+    /// surround plus the stones they captured.
+    Territory,
+}
+
+/// Information about the game which cannot be read from the board itself.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GameInfo {
+    /// How many white stones did black capture during the game.
+    pub black_captures: usize,
+    /// How many black stones did white capture during the game.
+    pub white_captures: usize,
+    /// Points given to white as a compensation for playing second.
+    pub komi: f32,
+    /// Positions as `(row, column)` of stones which the players agreed are
+    /// dead. These are removed from the board before the territory is
+    /// assigned and counted as captured. Positions which are out of the
+    /// board or empty are ignored.
+    pub dead_stones: HashSet<(usize, usize)>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Winner {
+    Black,
+    White,
+    /// Also known as jigo. Can only happen with integer komi.
+    Draw,
+}
+
+/// Final result of the game with all the numbers it was calculated from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScoreBreakdown {
+    pub rules: Rules,
+    /// Stones and territory counted on the board after the dead stones were
+    /// removed.
+    pub score: Score,
+    /// White stones captured by black during the game plus dead white stones.
+    pub black_prisoners: usize,
+    /// Black stones captured by white during the game plus dead black stones.
+    pub white_prisoners: usize,
+    pub komi: f32,
+    pub black_total: f32,
+    pub white_total: f32,
+}
+
+impl ScoreBreakdown {
+    pub fn winner(&self) -> Winner {
+        if self.black_total > self.white_total {
+            Winner::Black
+        } else if self.white_total > self.black_total {
+            Winner::White
+        } else {
+            Winner::Draw
+        }
+    }
+
+    /// By how many points did the winner win.
+    pub fn margin(&self) -> f32 {
+        (self.black_total - self.white_total).abs()
+    }
+}
+
+/// Removes the dead stones, assigns territory and calculates the final score
+/// of each player according to given rules. Komi always goes to white.
+pub fn count_result(
+    mut board: Board<Stone>,
+    rules: Rules,
+    game: &GameInfo,
+) -> (Board<Territory>, ScoreBreakdown) {
+    let Captures { by_black, by_white } =
+        dead::remove_dead_stones(&mut board, &game.dead_stones);
+    let black_prisoners = game.black_captures + by_black;
+    let white_prisoners = game.white_captures + by_white;
+
+    let (territory, score) = count_score(board);
+    let (black_points, white_points) = match rules {
+        Rules::Area => (score.black_area(), score.white_area()),
+        Rules::Territory => (
+            score.black_territory + black_prisoners,
+            score.white_territory + white_prisoners,
+        ),
+    };
+
+    let breakdown = ScoreBreakdown {
+        rules,
+        score,
+        black_prisoners,
+        white_prisoners,
+        komi: game.komi,
+        black_total: black_points as f32,
+        white_total: white_points as f32 + game.komi,
+    };
+
+    (territory, breakdown)
+}
+
+/// Finds all empty intersections connected to the starting one and decides
+/// who owns them based on which colours the region touches.
+fn empty_region(
+    stones: &Grid<Stone>,
+    visited: &mut Grid<bool>,
+    start: (usize, usize),
+) -> (Vec<(usize, usize)>, Territory) {
+    let mut touches_black = false;
+    let mut touches_white = false;
+    let mut region = Vec::new();
+
+    visited[start] = true;
+    let mut queue = vec![start];
+    while let Some(point) = queue.pop() {
+        region.push(point);
+
+        for neighbor in stones.neighbors(point) {
+            match stones[neighbor] {
+                Stone::Black => touches_black = true,
+                Stone::White => touches_white = true,
+                Stone::None if !visited[neighbor] => {
+                    visited[neighbor] = true;
+                    queue.push(neighbor);
+                }
+                Stone::None => (),
+            }
+        }
+    }
+
+    let owner = match (touches_black, touches_white) {
+        (true, false) => Territory::Black,
+        (false, true) => Territory::White,
+        _ => Territory::None,
+    };
+
+    (region, owner)
+}
+
+/// Empty intersections which border only one colour have no reason to be
+/// neutral. The others are seki if both players would put their own group
+/// into atari by playing there, and dame otherwise.
+fn neutral_reason(
+    stones: &Grid<Stone>,
+    point: (usize, usize),
+) -> Option<Neutral> {
+    let touches = |color| {
+        stones
+            .neighbors(point)
+            .any(|neighbor| stones[neighbor] == color)
+    };
+    if !touches(Stone::Black) || !touches(Stone::White) {
+        return None;
+    }
+
+    if is_self_atari(stones, point, Stone::Black)
+        && is_self_atari(stones, point, Stone::White)
+    {
+        Some(Neutral::Seki)
+    } else {
+        Some(Neutral::Dame)
+    }
+}
+
+/// Whether a stone of given colour played at given empty intersection would
+/// leave its group with a single liberty without capturing anything.
+fn is_self_atari(
+    stones: &Grid<Stone>,
+    point: (usize, usize),
+    color: Stone,
+) -> bool {
+    let mut liberties = HashSet::new();
+    for neighbor in stones.neighbors(point) {
+        if stones[neighbor] == Stone::None {
+            liberties.insert(neighbor);
+        } else if stones[neighbor] == color {
+            liberties.extend(group_liberties(stones, neighbor));
+        } else if group_liberties(stones, neighbor).len() == 1 {
+            // The only liberty of the opponent's group is the point itself,
+            // so playing there captures it.
+            return false;
+        }
+    }
+    liberties.remove(&point);
+
+    liberties.len() <= 1
+}
+
+/// Liberties of the group which the stone at given position belongs to.
+fn group_liberties(
+    stones: &Grid<Stone>,
+    at: (usize, usize),
+) -> HashSet<(usize, usize)> {
+    group::group_at(stones, at)
+        .map(|group| group.liberties)
+        .unwrap_or_default()
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    // Builds a 9x9 board from a text representation where `x` is black, `o`
+    // is white and `.` is an empty intersection.
+    pub(crate) fn nine_by_nine(rows: [&str; 9]) -> Board<Stone> {
+        let mut board = [[Stone::None; 9]; 9];
+        for (y, row) in rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                board[y][x] = match c {
+                    'x' => Stone::Black,
+                    'o' => Stone::White,
+                    _ => Stone::None,
+                };
+            }
+        }
+        Board::NineByNine(board)
+    }
+
     #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+    fn test_empty_board_has_no_territory() {
+        let (territory, score) =
+            count_score(Board::NineteenByNineteen([[Stone::None; 19]; 19]));
+
+        assert_eq!(Score::default(), score);
+        assert_eq!(
+            Board::NineteenByNineteen([[Territory::None; 19]; 19]),
+            territory
+        );
+    }
+
+    #[test]
+    fn test_single_stone_owns_the_board() {
+        let mut board = [[Stone::None; 13]; 13];
+        board[6][6] = Stone::Black;
+        let (territory, score) = count_score(Board::ThirteenByThirteen(board));
+
+        assert_eq!(1, score.black_stones);
+        assert_eq!(13 * 13 - 1, score.black_territory);
+        assert_eq!(13 * 13, score.black_area());
+        assert_eq!(0, score.white_area());
+        if let Board::ThirteenByThirteen(territory) = territory {
+            assert_eq!(Territory::None, territory[6][6]);
+            assert_eq!(Territory::Black, territory[0][0]);
+        } else {
+            panic!("The board size must not change");
+        }
+    }
+
+    #[test]
+    fn test_area_score() {
+        let board = nine_by_nine([
+            "..x.o....",
+            "..x.o....",
+            "..x.o....",
+            ".xx.oo...",
+            "xx...o...",
+            ".....oooo",
+            "xxxxxo...",
+            "....xo...",
+            "....xo...",
+        ]);
+        let (territory, score) = count_score(board);
+
+        assert_eq!(14, score.black_stones);
+        assert_eq!(13, score.white_stones);
+        // Top left corner and bottom left corner.
+        assert_eq!(7 + 8, score.black_territory);
+        // Top right corner and bottom right corner.
+        assert_eq!(18 + 9, score.white_territory);
+        assert_eq!(29, score.black_area());
+        assert_eq!(40, score.white_area());
+
+        if let Board::NineByNine(territory) = territory {
+            assert_eq!(Territory::Black, territory[0][0]);
+            assert_eq!(Territory::White, territory[0][8]);
+            assert_eq!(Territory::Black, territory[8][0]);
+            assert_eq!(Territory::White, territory[8][8]);
+            // The column between the two groups is neutral.
+            assert_eq!(Territory::None, territory[0][3]);
+            assert_eq!(Territory::None, territory[5][0]);
+        } else {
+            panic!("The board size must not change");
+        }
+        assert_eq!(Some(&Neutral::Dame), score.neutral.get(&(0, 3)));
+        // Borders black only, even though the region is neutral.
+        assert_eq!(None, score.neutral.get(&(5, 0)));
+    }
+
+    // Both inner groups have one eye and share the liberty at (0, 2).
+    pub(crate) fn seki_board() -> Board<Stone> {
+        nine_by_nine([
+            ".x.o.ox..",
+            "xxxooox..",
+            "oooxxxx..",
+            "o.ox.....",
+            "o.ox.....",
+            "o.ox.....",
+            "o.ox.....",
+            "o.ox.....",
+            "o.ox.....",
+        ])
+    }
+
+    #[test]
+    fn test_seki_is_neutral() {
+        let (territory, score) = count_score(seki_board());
+
+        if let Board::NineByNine(territory) = territory {
+            assert_eq!(Territory::None, territory[0][2]);
+            // The eyes of groups in seki aren't territory.
+            assert_eq!(Territory::None, territory[0][0]);
+            assert_eq!(Territory::None, territory[0][4]);
+        } else {
+            panic!("The board size must not change");
+        }
+        assert_eq!(3, score.neutral.len());
+        assert_eq!(Some(&Neutral::Seki), score.neutral.get(&(0, 2)));
+        assert_eq!(Some(&Neutral::Seki), score.neutral.get(&(0, 0)));
+        assert_eq!(Some(&Neutral::Seki), score.neutral.get(&(0, 4)));
+        assert_eq!(36, score.black_territory);
+        assert_eq!(6, score.white_territory);
+        assert_eq!((1, 1), (score.black_seki_eyes, score.white_seki_eyes));
+    }
+
+    #[test]
+    fn test_seki_eyes_count_towards_area_only() {
+        let (_, territory) =
+            count_result(seki_board(), Rules::Territory, &GameInfo::default());
+        assert_eq!(36.0, territory.black_total);
+        assert_eq!(6.0, territory.white_total);
+
+        let (_, area) =
+            count_result(seki_board(), Rules::Area, &GameInfo::default());
+        assert_eq!((area.score.black_stones + 36 + 1) as f32, area.black_total);
+        assert_eq!((area.score.white_stones + 6 + 1) as f32, area.white_total);
+    }
+
+    fn board_with_dead_white_stone() -> Board<Stone> {
+        nine_by_nine([
+            "..x.o....",
+            "..x.o....",
+            "..x.o....",
+            ".xx.oo...",
+            "xx...o...",
+            ".....oooo",
+            "xxxxxo...",
+            ".o..xo...",
+            "....xo...",
+        ])
+    }
+
+    #[test]
+    fn test_territory_score_with_prisoners_and_komi() {
+        let game = GameInfo {
+            black_captures: 3,
+            white_captures: 1,
+            komi: 6.5,
+            dead_stones: vec![(7, 1)].into_iter().collect(),
+        };
+        let (territory, breakdown) = count_result(
+            board_with_dead_white_stone(),
+            Rules::Territory,
+            &game,
+        );
+
+        assert_eq!(4, breakdown.black_prisoners);
+        assert_eq!(1, breakdown.white_prisoners);
+        assert_eq!(15, breakdown.score.black_territory);
+        assert_eq!(19.0, breakdown.black_total);
+        assert_eq!(34.5, breakdown.white_total);
+        assert_eq!(Winner::White, breakdown.winner());
+        assert_eq!(15.5, breakdown.margin());
+        if let Board::NineByNine(territory) = territory {
+            assert_eq!(Territory::Black, territory[7][1]);
+        } else {
+            panic!("The board size must not change");
+        }
+    }
+
+    #[test]
+    fn test_area_score_ignores_prisoners() {
+        let game = GameInfo {
+            black_captures: 3,
+            white_captures: 1,
+            komi: 6.5,
+            dead_stones: vec![(7, 1)].into_iter().collect(),
+        };
+        let (_, breakdown) =
+            count_result(board_with_dead_white_stone(), Rules::Area, &game);
+
+        assert_eq!(29.0, breakdown.black_total);
+        assert_eq!(46.5, breakdown.white_total);
+        assert_eq!(Winner::White, breakdown.winner());
+        assert_eq!(17.5, breakdown.margin());
+    }
+
+    #[test]
+    fn test_draw_with_integer_komi() {
+        let game = GameInfo {
+            komi: 0.0,
+            ..GameInfo::default()
+        };
+        let board = nine_by_nine([
+            "...x.o...",
+            "...x.o...",
+            "...x.o...",
+            "...x.o...",
+            "...x.o...",
+            "...x.o...",
+            "...x.o...",
+            "...x.o...",
+            "...x.o...",
+        ]);
+        let (_, breakdown) = count_result(board, Rules::Territory, &game);
+
+        assert_eq!(Winner::Draw, breakdown.winner());
+        assert_eq!(0.0, breakdown.margin());
+    }
+
+    #[test]
+    fn test_odd_board_sizes() {
+        let mut stones = Grid::new(7, Stone::None);
+        for row in 0..7 {
+            stones.set((row, 2), Stone::Black);
+            stones.set((row, 4), Stone::White);
+        }
+        let (territory, score) = count_grid_score(&stones);
+
+        assert_eq!(21, score.black_area());
+        assert_eq!(21, score.white_area());
+        assert_eq!(Territory::None, territory[(3, 3)]);
+        assert_eq!(Some(&Neutral::Dame), score.neutral.get(&(3, 3)));
+
+        let mut stones = Grid::new(11, Stone::None);
+        stones.set((5, 5), Stone::White);
+        let (territory, score) = count_grid_score(&stones);
+
+        assert_eq!(11 * 11, score.white_area());
+        assert_eq!(Territory::White, territory[(10, 10)]);
     }
 }
@@ -0,0 +1,597 @@
+//! Smart Game Format is what go editors use to store games and positions. See
+//! <https://www.red-bean.com/sgf/> for the specification. A position is
+//! written as a single node which sets up the stones on the board with the
+//! `AB` (add black) and `AW` (add white) properties. The territory is marked
+//! with `TB` (territory black) and `TW` (territory white).
+//!
+//! When reading a file, the setup properties `AB`, `AW` and `AE` (add empty)
+//! as well as the moves `B` and `W` are applied node by node along the main
+//! line, i.e. the first variation of each branch. Most games, and all the old
+//! ones, are played under Japanese rules which only forbid the simple ko, so
+//! any repetition is only illegal if the `RU` property names rules with
+//! superko. Files in the older formats write the identifiers as words, e.g.
+//! `AddBlack`, of which only the capital letters count.
+//!
+//! Intersections are written as two letters, column first and row second,
+//! starting with `a` in the top left corner. Hence the format can only
+//! represent boards with at most 52 lines.
+
+use crate::dead::Captures;
+use crate::game::{KoRule, MoveError, Position};
+use crate::{Board, Grid, Stone, Territory};
+use std::convert::TryFrom;
+use std::fmt::{self, Write};
+
+// Letters which represent a column or a row in the order of lines.
+const COORDINATES: &[u8] =
+    b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// Writes the position as SGF. If the territory is given, it's marked on the
+/// board as well.
+pub fn to_sgf(
+    stones: &Board<Stone>,
+    territory: Option<&Board<Territory>>,
+) -> String {
+    let territory = territory.map(|territory| Grid::from(territory.clone()));
+    grid_to_sgf(&Grid::from(stones.clone()), territory.as_ref())
+}
+
+/// Same as [`to_sgf`], but for boards of any size up to 52x52.
+///
+/// # Panics
+/// If the board is larger than 52x52, or if the territory is of a different
+/// size than the board.
+pub fn grid_to_sgf(
+    stones: &Grid<Stone>,
+    territory: Option<&Grid<Territory>>,
+) -> String {
+    let size = stones.size();
+    assert!(size <= COORDINATES.len(), "SGF supports boards up to 52x52");
+
+    let mut sgf = format!("(;FF[4]GM[1]CA[UTF-8]SZ[{}]", size);
+    let is_stone = |color| move |value: &Stone| *value == color;
+    write_points(&mut sgf, "AB", stones, is_stone(Stone::Black));
+    write_points(&mut sgf, "AW", stones, is_stone(Stone::White));
+
+    if let Some(territory) = territory {
+        assert_eq!(size, territory.size(), "Territory must fit the board");
+        let is_owner = |owner| move |value: &Territory| *value == owner;
+        write_points(&mut sgf, "TB", territory, is_owner(Territory::Black));
+        write_points(&mut sgf, "TW", territory, is_owner(Territory::White));
+    }
+
+    sgf.push(')');
+    sgf
+}
+
+/// Converts `(row, column)` position into SGF letters.
+pub(crate) fn point((row, column): (usize, usize)) -> String {
+    let letter = |line: usize| char::from(COORDINATES[line]);
+    format!("{}{}", letter(column), letter(row))
+}
+
+/// The main line of a game read from SGF.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SgfGame {
+    /// Stones on the board after the last node of the main line.
+    pub stones: Grid<Stone>,
+    /// Stones captured by each player's moves.
+    pub captures: Captures,
+    /// The `KM` property.
+    pub komi: Option<f32>,
+    /// The `RE` property as written in the file, e.g. `B+3.5` or `W+R`.
+    pub result: Option<String>,
+}
+
+impl SgfGame {
+    /// Returns `None` if the board isn't 9x9, 13x13 or 19x19.
+    pub fn board(&self) -> Option<Board<Stone>> {
+        Board::try_from(self.stones.clone()).ok()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SgfError {
+    /// The file isn't valid SGF. Contains the byte offset where parsing
+    /// failed.
+    Syntax(usize),
+    /// The game isn't go, i.e. `GM` isn't 1.
+    NotGo,
+    /// `SZ` isn't a number of lines between 1 and 52 or the board isn't
+    /// square.
+    InvalidSize(String),
+    /// An intersection which isn't on the board.
+    InvalidPoint(String),
+    /// A move of the main line breaks the rules. Moves are counted from 1.
+    IllegalMove { number: usize, error: MoveError },
+}
+
+impl fmt::Display for SgfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SgfError::Syntax(offset) => {
+                write!(f, "Invalid SGF syntax at byte {}", offset)
+            }
+            SgfError::NotGo => write!(f, "The SGF doesn't contain a go game"),
+            SgfError::InvalidSize(size) => {
+                write!(f, "Invalid board size '{}'", size)
+            }
+            SgfError::InvalidPoint(point) => {
+                write!(f, "Invalid intersection '{}'", point)
+            }
+            SgfError::IllegalMove { number, error } => {
+                write!(f, "Move {}: {}", number, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SgfError {}
+
+/// Reads the first game in the file and replays its main line. Moves are
+/// checked against the ko rule of the rules in the `RU` property, see
+/// `ko_rule`.
+pub fn from_sgf(sgf: &str) -> Result<SgfGame, SgfError> {
+    let nodes = Parser { sgf, offset: 0 }.game_tree()?;
+    let root = nodes.first().ok_or(SgfError::Syntax(0))?;
+
+    if let Some(game) = property(root, "GM") {
+        if game.trim() != "1" {
+            return Err(SgfError::NotGo);
+        }
+    }
+    let size = match property(root, "SZ") {
+        None => 19,
+        Some(size) => match size.trim().parse() {
+            Ok(size) if size > 0 && size <= COORDINATES.len() => size,
+            _ => return Err(SgfError::InvalidSize(size.to_string())),
+        },
+    };
+
+    let ko_rule = ko_rule(property(root, "RU"));
+    let mut position = Position::new(size, ko_rule);
+    let mut komi = None;
+    let mut result = None;
+    let mut moves = 0;
+    for node in &nodes {
+        for (identifier, values) in node {
+            let stone = match identifier.as_str() {
+                "AB" | "B" => Stone::Black,
+                "AW" | "W" => Stone::White,
+                "AE" => Stone::None,
+                "KM" => {
+                    komi = values[0].trim().parse().ok();
+                    continue;
+                }
+                "RE" => {
+                    result = Some(values[0].clone());
+                    continue;
+                }
+                _ => continue,
+            };
+
+            if identifier.len() == 2 {
+                for value in values {
+                    for at in points(value, size)? {
+                        position.setup(at, stone).map_err(|_| {
+                            SgfError::InvalidPoint(value.to_string())
+                        })?;
+                    }
+                }
+                continue;
+            }
+
+            moves += 1;
+            let value = values[0].as_str();
+            // An empty value is a pass, and so is `tt` on boards which don't
+            // have the twentieth line.
+            if value.is_empty() || (value == "tt" && size <= 19) {
+                position.pass();
+                continue;
+            }
+            let at = parse_point(value, size)?;
+            position.play(stone, at).map_err(|error| {
+                SgfError::IllegalMove {
+                    number: moves,
+                    error,
+                }
+            })?;
+        }
+    }
+
+    Ok(SgfGame {
+        stones: position.stones().clone(),
+        captures: position.captures(),
+        komi,
+        result,
+    })
+}
+
+/// Properties of one node in the order they were written, each with at least
+/// one value.
+type Node = Vec<(String, Vec<String>)>;
+
+fn property<'a>(node: &'a Node, identifier: &str) -> Option<&'a str> {
+    node.iter()
+        .find(|(id, _)| id == identifier)
+        .map(|(_, values)| values[0].as_str())
+}
+
+/// Chinese, AGA, Ing and New Zealand rules forbid any repetition of a
+/// position. Japanese and Korean rules, as well as games which don't say, only
+/// forbid the simple ko and allow e.g. a triple ko to repeat.
+fn ko_rule(rules: Option<&str>) -> KoRule {
+    let rules = rules.map(|rules| rules.trim().to_ascii_lowercase());
+    match rules.as_deref() {
+        Some("chinese") | Some("aga") | Some("goe") | Some("nz") => {
+            KoRule::PositionalSuperko
+        }
+        _ => KoRule::Simple,
+    }
+}
+
+/// Converts SGF letters into `(row, column)` position.
+fn parse_point(value: &str, size: usize) -> Result<(usize, usize), SgfError> {
+    let line =
+        |letter| COORDINATES.iter().take(size).position(|c| *c == letter);
+    match value.as_bytes() {
+        [column, row] => match (line(*row), line(*column)) {
+            (Some(row), Some(column)) => Ok((row, column)),
+            _ => Err(SgfError::InvalidPoint(value.to_string())),
+        },
+        _ => Err(SgfError::InvalidPoint(value.to_string())),
+    }
+}
+
+/// Setup properties can list a rectangle of intersections at once, e.g.
+/// `aa:cc` stands for the nine intersections in the top left corner.
+fn points(value: &str, size: usize) -> Result<Vec<(usize, usize)>, SgfError> {
+    let (from, to) = match value.find(':') {
+        Some(colon) => (
+            parse_point(&value[..colon], size)?,
+            parse_point(&value[colon + 1..], size)?,
+        ),
+        None => {
+            let point = parse_point(value, size)?;
+            (point, point)
+        }
+    };
+
+    let rows = from.0.min(to.0)..=from.0.max(to.0);
+    Ok(rows
+        .flat_map(|row| {
+            (from.1.min(to.1)..=from.1.max(to.1)).map(move |c| (row, c))
+        })
+        .collect())
+}
+
+struct Parser<'a> {
+    sgf: &'a str,
+    offset: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// Reads a game tree enclosed in parentheses and returns the nodes of its
+    /// main line. Variations other than the first one are skipped.
+    fn game_tree(&mut self) -> Result<Vec<Node>, SgfError> {
+        self.expect('(')?;
+        let mut nodes = Vec::new();
+        loop {
+            match self.peek() {
+                Some(';') => {
+                    self.offset += 1;
+                    nodes.push(self.node()?);
+                }
+                Some('(') => {
+                    nodes.extend(self.game_tree()?);
+                    while self.peek() == Some('(') {
+                        self.game_tree()?;
+                    }
+                    self.expect(')')?;
+                    return Ok(nodes);
+                }
+                Some(')') => {
+                    self.offset += 1;
+                    return Ok(nodes);
+                }
+                _ => return Err(SgfError::Syntax(self.offset)),
+            }
+        }
+    }
+
+    fn node(&mut self) -> Result<Node, SgfError> {
+        let mut properties = Vec::new();
+        while let Some(c) = self.peek() {
+            if !c.is_ascii_alphabetic() {
+                break;
+            }
+
+            // Lowercase letters are left out of the identifier.
+            let rest = &self.sgf[self.offset..];
+            let length = rest
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(rest.len());
+            let identifier: String = rest[..length]
+                .chars()
+                .filter(char::is_ascii_uppercase)
+                .collect();
+            if identifier.is_empty() {
+                return Err(SgfError::Syntax(self.offset));
+            }
+            self.offset += length;
+
+            let mut values = Vec::new();
+            while self.peek() == Some('[') {
+                values.push(self.value()?);
+            }
+            if values.is_empty() {
+                return Err(SgfError::Syntax(self.offset));
+            }
+            properties.push((identifier, values));
+        }
+
+        Ok(properties)
+    }
+
+    /// Reads a value in brackets. A backslash escapes the next character, so
+    /// that the value can contain `]`.
+    fn value(&mut self) -> Result<String, SgfError> {
+        self.expect('[')?;
+        let mut value = String::new();
+        let mut chars = self.sgf[self.offset..].char_indices();
+        while let Some((index, c)) = chars.next() {
+            match c {
+                ']' => {
+                    self.offset += index + 1;
+                    return Ok(value);
+                }
+                '\\' => {
+                    if let Some((_, escaped)) = chars.next() {
+                        value.push(escaped);
+                    }
+                }
+                c => value.push(c),
+            }
+        }
+
+        Err(SgfError::Syntax(self.sgf.len()))
+    }
+
+    /// Skips whitespace and returns the next character without consuming it.
+    fn peek(&mut self) -> Option<char> {
+        let rest = &self.sgf[self.offset..];
+        let trimmed = rest.trim_start();
+        self.offset += rest.len() - trimmed.len();
+        trimmed.chars().next()
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), SgfError> {
+        if self.peek() == Some(expected) {
+            self.offset += expected.len_utf8();
+            Ok(())
+        } else {
+            Err(SgfError::Syntax(self.offset))
+        }
+    }
+}
+
+/// Appends a property with a value for each intersection which satisfies the
+/// predicate. If there's no such intersection, the property is left out.
+fn write_points<T>(
+    sgf: &mut String,
+    property: &str,
+    grid: &Grid<T>,
+    predicate: impl Fn(&T) -> bool,
+) {
+    let points: Vec<_> = grid
+        .iter()
+        .filter(|(_, value)| predicate(value))
+        .map(|(at, _)| at)
+        .collect();
+    if points.is_empty() {
+        return;
+    }
+
+    sgf.push_str(property);
+    for at in points {
+        write!(sgf, "[{}]", point(at)).expect("Cannot write to a string");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::count_score;
+    use crate::tests::nine_by_nine;
+
+    #[test]
+    fn test_position_to_sgf() {
+        let board = nine_by_nine([
+            "..x.o....",
+            ".........",
+            ".........",
+            ".........",
+            ".........",
+            ".........",
+            ".........",
+            ".........",
+            "o.......x",
+        ]);
+
+        assert_eq!(
+            "(;FF[4]GM[1]CA[UTF-8]SZ[9]AB[ca][ii]AW[ea][ai])",
+            to_sgf(&board, None)
+        );
+    }
+
+    #[test]
+    fn test_territory_to_sgf() {
+        let board = nine_by_nine([
+            ".x.o.....",
+            "xx.oooooo",
+            ".........",
+            ".........",
+            ".........",
+            ".........",
+            ".........",
+            ".........",
+            ".........",
+        ]);
+        let (territory, _) = count_score(board.clone());
+
+        assert_eq!(
+            "(;FF[4]GM[1]CA[UTF-8]SZ[9]AB[ba][ab][bb]AW[da][db][eb][fb][gb]\
+            [hb][ib]TB[aa]TW[ea][fa][ga][ha][ia])",
+            to_sgf(&board, Some(&territory))
+        );
+    }
+
+    #[test]
+    fn test_odd_board_size() {
+        let mut stones = Grid::new(7, Stone::None);
+        stones.set((3, 3), Stone::Black);
+
+        assert_eq!(
+            "(;FF[4]GM[1]CA[UTF-8]SZ[7]AB[dd])",
+            grid_to_sgf(&stones, None)
+        );
+    }
+
+    #[test]
+    fn test_setup_from_sgf() {
+        let game = from_sgf(
+            "(;FF[4]GM[1]SZ[9]C[A comment with \\] inside]
+              AB[aa:cb][ii]AW[ea]
+              ;AE[ba]
+              (;AW[ee])(;AB[ee]))",
+        )
+        .expect("Valid SGF");
+
+        let expected = nine_by_nine([
+            "x.x.o....",
+            "xxx......",
+            ".........",
+            ".........",
+            "....o....",
+            ".........",
+            ".........",
+            ".........",
+            "........x",
+        ]);
+        assert_eq!(Some(expected), game.board());
+        assert_eq!(None, game.komi);
+    }
+
+    #[test]
+    fn test_moves_from_sgf() {
+        let game =
+            from_sgf("(;SZ[9]KM[6.5];B[ba];W[aa];B[ab];W[];B[cc];W[tt];B[ca])")
+                .expect("Valid SGF");
+
+        let mut expected = Grid::new(9, Stone::None);
+        for at in &[(0, 1), (1, 0), (2, 2), (0, 2)] {
+            expected.set(*at, Stone::Black);
+        }
+        assert_eq!(expected, game.stones);
+        assert_eq!(
+            Captures {
+                by_black: 1,
+                by_white: 0
+            },
+            game.captures
+        );
+        assert_eq!(Some(6.5), game.komi);
+    }
+
+    #[test]
+    fn test_triple_ko_from_sgf() {
+        // Three kos are taken in turn, so that the sixth move brings back the
+        // position from the start, and the seventh one the position after the
+        // first move.
+        let sgf = |rules| {
+            format!(
+                "(;SZ[9]RU[{}]AB[ba][ab][bc][ha][gb][ib][hc][be][af][bg]
+                  AW[ca][bb][db][cc][ga][fb][gc][ce][bf][df][cg]
+                  ;B[cb];W[hb];B[cf];W[bb];B[gb];W[bf];B[cb])",
+                rules
+            )
+        };
+
+        let game = from_sgf(&sgf("Japanese")).expect("Triple ko is legal");
+        assert_eq!(Stone::Black, game.stones[(1, 2)]);
+        assert_eq!(Stone::None, game.stones[(1, 1)]);
+        assert!(matches!(
+            from_sgf(&sgf("Chinese")),
+            Err(SgfError::IllegalMove { number: 6, .. })
+        ));
+    }
+
+    #[test]
+    fn test_lowercase_identifiers_from_sgf() {
+        let game = from_sgf("(;GaMe[1]SiZe[9]AddBlack[aa][bb]AddWhite[cc])")
+            .expect("Valid SGF");
+
+        let mut expected = Grid::new(9, Stone::None);
+        expected.set((0, 0), Stone::Black);
+        expected.set((1, 1), Stone::Black);
+        expected.set((2, 2), Stone::White);
+        assert_eq!(expected, game.stones);
+    }
+
+    #[test]
+    fn test_sgf_round_trip() {
+        let board = crate::tests::seki_board();
+
+        assert_eq!(
+            Some(board.clone()),
+            from_sgf(&to_sgf(&board, None)).unwrap().board()
+        );
+    }
+
+    #[test]
+    fn test_invalid_sgf() {
+        assert_eq!(Err(SgfError::Syntax(13)), from_sgf("(;SZ[9];B[aa)"));
+        assert_eq!(Err(SgfError::NotGo), from_sgf("(;GM[2]SZ[8])"));
+        assert_eq!(
+            Err(SgfError::InvalidSize("19:13".to_string())),
+            from_sgf("(;SZ[19:13])")
+        );
+        assert_eq!(
+            Err(SgfError::InvalidPoint("jj".to_string())),
+            from_sgf("(;SZ[9];B[jj])")
+        );
+        assert_eq!(
+            Err(SgfError::IllegalMove {
+                number: 2,
+                error: MoveError::Occupied
+            }),
+            from_sgf("(;SZ[9];B[aa];W[aa])")
+        );
+    }
+
+    // A finished game whose result is known is replayed and scored, which is
+    // how the scoring is checked against collections of real games.
+    #[test]
+    fn test_score_finished_game() {
+        let moves: String = "abcdefghi"
+            .chars()
+            .map(|row| format!(";B[d{}];W[f{}]", row, row))
+            .collect();
+        let game = from_sgf(&format!("(;SZ[9]KM[7]RE[W+7]{})", moves))
+            .expect("Valid SGF");
+
+        let info = crate::GameInfo {
+            komi: game.komi.unwrap(),
+            ..crate::GameInfo::default()
+        };
+        let (_, breakdown) = crate::count_result(
+            game.board().unwrap(),
+            crate::Rules::Area,
+            &info,
+        );
+
+        assert_eq!(Some("W+7".to_string()), game.result);
+        assert_eq!(crate::Winner::White, breakdown.winner());
+        assert_eq!(7.0, breakdown.margin());
+    }
+}